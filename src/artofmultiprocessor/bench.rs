@@ -0,0 +1,303 @@
+use super::ch2::{spawn, Bakery, FilterLock, Lock, Peterson, ThreadIds, TournamentLock};
+use super::ch7::{
+    ALock, CLHLock, CohortLock, CompositeFastPathLock, CompositeLock, HBOLock, MCSLock, TOLock,
+    TTASLock,
+};
+use std::{
+    io::{self, Write},
+    sync::{Arc, Barrier},
+    time::Instant,
+};
+
+pub const LOCKS: &[&str] = &[
+    "peterson",
+    "filter",
+    "bakery",
+    "tournament",
+    "ttas",
+    "alock",
+    "clh",
+    "mcs",
+    "to",
+    "hbo",
+    "cohort",
+    "composite",
+    "composite-fast",
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format {
+    Csv,
+    Json,
+}
+
+// every combination of locks x threads x cs x iters is one run.
+// cs is the number of spin iterations inside the critical section, iters the acquisitions per thread
+#[derive(Debug)]
+pub struct Config {
+    pub locks: Vec<String>,
+    pub threads: Vec<usize>,
+    pub cs: Vec<usize>,
+    pub iters: Vec<usize>,
+    pub format: Format,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            locks: LOCKS.iter().map(|l| l.to_string()).collect(),
+            threads: vec![1, 2, 4, 8],
+            cs: vec![0, 100],
+            iters: vec![1000],
+            format: Format::Csv,
+        }
+    }
+}
+
+fn parse_list<T: std::str::FromStr>(flag: &str, value: Option<String>) -> Result<Vec<T>, String> {
+    let value = value.ok_or(format!("{} needs a value", flag))?;
+    value
+        .split(',')
+        .map(|v| {
+            v.parse()
+                .map_err(|_| format!("bad value {:?} for {}", v, flag))
+        })
+        .collect()
+}
+
+impl Config {
+    // --locks ttas,mcs --threads 1,2,4 --cs 0,100 --iters 1000 --format csv|json
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut args = args.into_iter();
+        while let Some(flag) = args.next() {
+            match flag.as_str() {
+                "--locks" => {
+                    config.locks = parse_list(&flag, args.next())?;
+                    if let Some(l) = config.locks.iter().find(|l| !LOCKS.contains(&l.as_str())) {
+                        return Err(format!("unknown lock {}, one of {}", l, LOCKS.join(",")));
+                    }
+                }
+                "--threads" => config.threads = parse_list(&flag, args.next())?,
+                "--cs" => config.cs = parse_list(&flag, args.next())?,
+                "--iters" => config.iters = parse_list(&flag, args.next())?,
+                "--format" => {
+                    config.format = match args.next().as_deref() {
+                        Some("csv") => Format::Csv,
+                        Some("json") => Format::Json,
+                        other => return Err(format!("bad format {:?}, csv or json", other)),
+                    }
+                }
+                _ => return Err(format!("unknown argument {}", flag)),
+            }
+        }
+        if config.threads.contains(&0) || config.iters.contains(&0) {
+            return Err("--threads and --iters must be positive".to_string());
+        }
+        Ok(config)
+    }
+}
+
+// latencies are the time from calling lock until holding it.
+// jain is jain's fairness index over the per thread throughput, 1 is perfectly fair
+#[derive(Debug)]
+pub struct Sample {
+    pub lock: String,
+    pub threads: usize,
+    pub cs: usize,
+    pub iters: usize,
+    pub seconds: f64,
+    pub throughput: f64,
+    pub p50_ns: u64,
+    pub p90_ns: u64,
+    pub p99_ns: u64,
+    pub max_ns: u64,
+    pub jain: f64,
+}
+
+impl Sample {
+    pub const CSV_HEADER: &'static str =
+        "lock,threads,cs,iters,seconds,throughput,p50_ns,p90_ns,p99_ns,max_ns,jain";
+
+    pub fn to_csv(&self) -> String {
+        format!(
+            "{},{},{},{},{:.6},{:.1},{},{},{},{},{:.4}",
+            self.lock,
+            self.threads,
+            self.cs,
+            self.iters,
+            self.seconds,
+            self.throughput,
+            self.p50_ns,
+            self.p90_ns,
+            self.p99_ns,
+            self.max_ns,
+            self.jain
+        )
+    }
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"lock\":\"{}\",\"threads\":{},\"cs\":{},\"iters\":{},\"seconds\":{:.6},\"throughput\":{:.1},\"p50_ns\":{},\"p90_ns\":{},\"p99_ns\":{},\"max_ns\":{},\"jain\":{:.4}}}",
+            self.lock,
+            self.threads,
+            self.cs,
+            self.iters,
+            self.seconds,
+            self.throughput,
+            self.p50_ns,
+            self.p90_ns,
+            self.p99_ns,
+            self.max_ns,
+            self.jain
+        )
+    }
+}
+
+fn percentile(sorted: &[u64], p: f64) -> u64 {
+    let i = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[i]
+}
+
+fn measure<L>(name: &str, lock: L, threads: usize, cs: usize, iters: usize) -> Sample
+where
+    L: Lock<usize> + Send + Sync + 'static,
+{
+    let lo = Arc::new(lock);
+    let spare = Arc::new(ThreadIds::new(threads));
+    let barrier = Arc::new(Barrier::new(threads));
+    let mut jhs = vec![];
+    for _ in 0..threads {
+        let b = barrier.clone();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let jh = spawn(&lo, &spare, move |l, id| {
+            let mut latencies = Vec::with_capacity(iters);
+            b.wait();
+            let begin = Instant::now();
+            for _ in 0..iters {
+                let t = Instant::now();
+                let mut num = l.lock(id);
+                latencies.push(t.elapsed().as_nanos() as u64);
+                for _ in 0..cs {
+                    std::hint::black_box(&mut *num);
+                }
+                *num += 1;
+            }
+            tx.send((latencies, begin, Instant::now())).unwrap();
+        });
+        jhs.push((jh, rx))
+    }
+
+    // the run goes from the first thread starting to the last one finishing,
+    // spawning and joining doesn't count
+    let mut latencies = vec![];
+    let mut rates = vec![];
+    let mut start: Option<Instant> = None;
+    let mut end: Option<Instant> = None;
+    for (jh, rx) in jhs {
+        jh.join().unwrap();
+        let (l, begin, finish) = rx.recv().unwrap();
+        latencies.extend(l);
+        rates.push(iters as f64 / (finish - begin).as_secs_f64());
+        start = Some(start.map_or(begin, |s| s.min(begin)));
+        end = Some(end.map_or(finish, |e| e.max(finish)));
+    }
+    let seconds = (end.unwrap() - start.unwrap()).as_secs_f64();
+    let mut id = lo.ids().unwrap_or(&spare).register();
+    assert_eq!(*lo.lock(&mut id), threads * iters);
+
+    latencies.sort_unstable();
+    Sample {
+        lock: name.to_string(),
+        threads,
+        cs,
+        iters,
+        seconds,
+        throughput: (threads * iters) as f64 / seconds,
+        p50_ns: percentile(&latencies, 0.5),
+        p90_ns: percentile(&latencies, 0.9),
+        p99_ns: percentile(&latencies, 0.99),
+        max_ns: *latencies.last().unwrap(),
+        jain: jain(&rates),
+    }
+}
+
+// jain's fairness index, (sum x)^2 / (n * sum x^2). 1 if all x are equal, 1/n if one x is everything
+pub fn jain(xs: &[f64]) -> f64 {
+    let sum: f64 = xs.iter().sum();
+    let squares: f64 = xs.iter().map(|x| x * x).sum();
+    sum * sum / (xs.len() as f64 * squares)
+}
+
+// None if the lock can't run with that many threads
+pub fn bench(name: &str, threads: usize, cs: usize, iters: usize) -> Option<Sample> {
+    let sample = match name {
+        "peterson" if threads <= 2 => measure(name, Peterson::new(0), threads, cs, iters),
+        "peterson" => return None,
+        "filter" => measure(name, FilterLock::new(0, threads), threads, cs, iters),
+        "bakery" => measure(name, Bakery::new(0, threads), threads, cs, iters),
+        "tournament" => measure(name, TournamentLock::new(0, threads), threads, cs, iters),
+        "ttas" => measure(name, TTASLock::new(0), threads, cs, iters),
+        "alock" => measure(name, ALock::new(0, threads), threads, cs, iters),
+        "clh" => measure(name, CLHLock::new(0), threads, cs, iters),
+        "mcs" => measure(name, MCSLock::new(0), threads, cs, iters),
+        "to" => measure(name, TOLock::new(0), threads, cs, iters),
+        "hbo" => measure(name, HBOLock::new(0, 2), threads, cs, iters),
+        "cohort" => measure(name, CohortLock::new(0, 2, 64), threads, cs, iters),
+        "composite" => measure(name, CompositeLock::new(0, 4), threads, cs, iters),
+        "composite-fast" => measure(name, CompositeFastPathLock::new(0, 4), threads, cs, iters),
+        _ => return None,
+    };
+    Some(sample)
+}
+
+pub fn run(config: &Config, out: &mut impl Write) -> io::Result<()> {
+    let mut first = true;
+    match config.format {
+        Format::Csv => writeln!(out, "{}", Sample::CSV_HEADER)?,
+        Format::Json => write!(out, "[")?,
+    }
+    for lock in &config.locks {
+        for &threads in &config.threads {
+            for &cs in &config.cs {
+                for &iters in &config.iters {
+                    let sample = match bench(lock, threads, cs, iters) {
+                        Some(s) => s,
+                        None => continue,
+                    };
+                    match config.format {
+                        Format::Csv => writeln!(out, "{}", sample.to_csv())?,
+                        Format::Json => {
+                            let sep = if first { "" } else { "," };
+                            write!(out, "{}\n  {}", sep, sample.to_json())?
+                        }
+                    }
+                    first = false;
+                    out.flush()?;
+                }
+            }
+        }
+    }
+    if config.format == Format::Json {
+        writeln!(out, "\n]")?;
+    }
+    Ok(())
+}
+
+#[test]
+fn bench_csv() {
+    let config = Config::from_args(
+        "--locks peterson,mcs,tournament --threads 2,3 --cs 0,10 --iters 50"
+            .split(' ')
+            .map(String::from),
+    )
+    .unwrap();
+    let mut out = vec![];
+    run(&config, &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let lines: Vec<_> = out.lines().collect();
+    assert_eq!(lines[0], Sample::CSV_HEADER);
+    // peterson skips 3 threads
+    assert_eq!(lines.len(), 1 + 4 + 4 + 2);
+    assert!(lines[1].starts_with("peterson,2,0,50,"));
+    assert!(Config::from_args(vec!["--locks".to_string(), "nope".to_string()]).is_err());
+}
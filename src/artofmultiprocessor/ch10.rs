@@ -1,7 +1,705 @@
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::{
-    cell::{Cell, UnsafeCell},
-    sync::Arc,
-    thread,
-};
-use thread::JoinHandle;
+use super::ch2::{AnyTidLock, Lock};
+use super::ch7::MCSLock;
+use super::ch8::Condition;
+use super::epoch::{Collector, Guard};
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::thread;
+
+struct Node<T> {
+    value: Option<T>,
+    next: AtomicPtr<Node<T>>,
+}
+impl<T> Node<T> {
+    fn alloc(value: Option<T>) -> *mut Node<T> {
+        Box::into_raw(Box::new(Node {
+            value,
+            next: AtomicPtr::new(std::ptr::null_mut()),
+        }))
+    }
+}
+
+// bounded partial queue. enqueuers and dequeuers have their own lock, the enq lock guards the
+// tail and the deq lock the head sentinel, so both ends can work at the same time.
+// they only meet in size: an enq that fills the first slot wakes the dequeuers and
+// a deq that frees the last slot wakes the enqueuers, each under the other side's lock
+// so the waiter can't miss it
+pub struct BoundedQueue<T> {
+    enq_lock: MCSLock<*mut Node<T>>,
+    not_full: Condition,
+    deq_lock: MCSLock<*mut Node<T>>,
+    not_empty: Condition,
+    size: AtomicUsize,
+    capacity: usize,
+}
+unsafe impl<T: Send> Send for BoundedQueue<T> {}
+unsafe impl<T: Send> Sync for BoundedQueue<T> {}
+
+impl<T> BoundedQueue<T> {
+    pub fn new(capacity: usize) -> BoundedQueue<T> {
+        assert!(
+            capacity > 0,
+            "BoundedQueue needs room for at least one item"
+        );
+        let sentinel = Node::alloc(None);
+        BoundedQueue {
+            enq_lock: MCSLock::new(sentinel),
+            not_full: Condition::new(),
+            deq_lock: MCSLock::new(sentinel),
+            not_empty: Condition::new(),
+            size: AtomicUsize::new(0),
+            capacity,
+        }
+    }
+    // caller holds the enq lock and made sure there is room
+    fn push(&self, tail: &mut *mut Node<T>, a: T) -> bool {
+        let node = Node::alloc(Some(a));
+        unsafe { &**tail }.next.store(node, Ordering::Release);
+        *tail = node;
+        self.size.fetch_add(1, Ordering::AcqRel) == 0
+    }
+    // caller holds the deq lock and made sure there is an item
+    fn pop(&self, head: &mut *mut Node<T>) -> (T, bool) {
+        let sentinel = *head;
+        let next = unsafe { &*sentinel }.next.load(Ordering::Acquire);
+        let a = unsafe { (*next).value.take() }.unwrap();
+        *head = next;
+        unsafe { drop(Box::from_raw(sentinel)) };
+        (a, self.size.fetch_sub(1, Ordering::AcqRel) == self.capacity)
+    }
+    fn wake_dequeuers(&self) {
+        let _head = self.deq_lock.lock_any();
+        self.not_empty.notify_all()
+    }
+    fn wake_enqueuers(&self) {
+        let _tail = self.enq_lock.lock_any();
+        self.not_full.notify_all()
+    }
+    // blocks while the queue is full
+    pub fn enq(&self, a: T) {
+        let mut tail = self.enq_lock.lock_any();
+        while self.size.load(Ordering::Acquire) == self.capacity {
+            self.not_full.wait(&mut tail)
+        }
+        let wake = self.push(&mut tail, a);
+        drop(tail);
+        if wake {
+            self.wake_dequeuers()
+        }
+    }
+    // hands the item back if the queue is full
+    pub fn try_enq(&self, a: T) -> Result<(), T> {
+        let mut tail = self.enq_lock.lock_any();
+        if self.size.load(Ordering::Acquire) == self.capacity {
+            return Err(a);
+        }
+        let wake = self.push(&mut tail, a);
+        drop(tail);
+        if wake {
+            self.wake_dequeuers()
+        }
+        Ok(())
+    }
+    // blocks while the queue is empty
+    pub fn deq(&self) -> T {
+        let mut head = self.deq_lock.lock_any();
+        while self.size.load(Ordering::Acquire) == 0 {
+            self.not_empty.wait(&mut head)
+        }
+        let (a, wake) = self.pop(&mut head);
+        drop(head);
+        if wake {
+            self.wake_enqueuers()
+        }
+        a
+    }
+    pub fn try_deq(&self) -> Option<T> {
+        let mut head = self.deq_lock.lock_any();
+        if self.size.load(Ordering::Acquire) == 0 {
+            return None;
+        }
+        let (a, wake) = self.pop(&mut head);
+        drop(head);
+        if wake {
+            self.wake_enqueuers()
+        }
+        Some(a)
+    }
+    pub fn len(&self) -> usize {
+        self.size.load(Ordering::Acquire)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+impl<T> Drop for BoundedQueue<T> {
+    fn drop(&mut self) {
+        let mut node = unsafe { *self.deq_lock.value().get() };
+        while !node.is_null() {
+            let b = unsafe { Box::from_raw(node) };
+            node = b.next.load(Ordering::Relaxed);
+        }
+    }
+}
+
+// producers push increasing numbers through a small queue, every consumer
+// has to see each producer's numbers in order and together they see all of them
+#[test]
+fn bounded_queue() {
+    use std::{sync::Arc, thread};
+    let producers = 3;
+    let items = 2000;
+    let q = Arc::new(BoundedQueue::new(4));
+    let mut jhs = vec![];
+    for p in 0..producers {
+        let q = q.clone();
+        jhs.push(thread::spawn(move || {
+            for i in 0..items {
+                q.enq((p, i));
+            }
+        }));
+    }
+    let consumers: Vec<_> = (0..2)
+        .map(|_| {
+            let q = q.clone();
+            thread::spawn(move || {
+                let mut last = vec![None; producers];
+                let mut seen = 0;
+                for _ in 0..producers * items / 2 {
+                    let (p, i) = q.deq();
+                    assert!(last[p] < Some(i));
+                    last[p] = Some(i);
+                    seen += i;
+                }
+                seen
+            })
+        })
+        .collect();
+    for jh in jhs {
+        jh.join().unwrap()
+    }
+    let seen: usize = consumers.into_iter().map(|jh| jh.join().unwrap()).sum();
+    assert_eq!(seen, producers * items * (items - 1) / 2);
+    assert!(q.is_empty());
+}
+
+#[test]
+fn bounded_queue_try() {
+    use std::{sync::Arc, thread, time::Duration};
+    let q = Arc::new(BoundedQueue::new(2));
+    assert_eq!(q.try_deq(), None);
+    assert_eq!(q.try_enq(1), Ok(()));
+    assert_eq!(q.try_enq(2), Ok(()));
+    assert_eq!(q.try_enq(3), Err(3));
+    assert_eq!(q.len(), q.capacity());
+    // a blocked producer gets through once a slot frees up
+    let q1 = q.clone();
+    let producer = thread::spawn(move || q1.enq(3));
+    thread::sleep(Duration::from_millis(20));
+    assert_eq!(q.len(), 2);
+    assert_eq!(q.deq(), 1);
+    producer.join().unwrap();
+    assert_eq!(q.try_deq(), Some(2));
+    assert_eq!(q.deq(), 3);
+    assert_eq!(q.try_deq(), None);
+    q.enq(4);
+}
+
+// michael-scott unbounded lock-free queue. head is a sentinel, the first item sits in its
+// successor. tail may lag one node behind, whoever notices moves it on before doing its own work.
+// a dequeuer that swings head retires the old sentinel to the collector
+pub struct LockFreeQueue<T> {
+    head: AtomicPtr<LFNode<T>>,
+    tail: AtomicPtr<LFNode<T>>,
+    collector: Collector,
+}
+// value is taken by the one dequeuer whose cas made this node the sentinel
+struct LFNode<T> {
+    value: UnsafeCell<Option<T>>,
+    next: AtomicPtr<LFNode<T>>,
+}
+unsafe impl<T: Send> Send for LockFreeQueue<T> {}
+unsafe impl<T: Send> Sync for LockFreeQueue<T> {}
+
+impl<T> LFNode<T> {
+    fn alloc(value: Option<T>) -> *mut LFNode<T> {
+        Box::into_raw(Box::new(LFNode {
+            value: UnsafeCell::new(value),
+            next: AtomicPtr::new(std::ptr::null_mut()),
+        }))
+    }
+}
+
+impl<T> LockFreeQueue<T> {
+    pub fn new() -> LockFreeQueue<T> {
+        let sentinel = LFNode::alloc(None);
+        LockFreeQueue {
+            head: AtomicPtr::new(sentinel),
+            tail: AtomicPtr::new(sentinel),
+            collector: Collector::new(),
+        }
+    }
+    pub fn enq(&self, a: T) {
+        let node = LFNode::alloc(Some(a));
+        let _guard = self.collector.pin();
+        loop {
+            let last = self.tail.load(Ordering::Acquire);
+            let next = unsafe { &*last }.next.load(Ordering::Acquire);
+            if last != self.tail.load(Ordering::Acquire) {
+                continue;
+            }
+            if next.is_null() {
+                if unsafe { &*last }
+                    .next
+                    .compare_exchange(next, node, Ordering::AcqRel, Ordering::Acquire)
+                    .is_ok()
+                {
+                    // fine if this fails, somebody already helped
+                    let _ =
+                        self.tail
+                            .compare_exchange(last, node, Ordering::AcqRel, Ordering::Acquire);
+                    return;
+                }
+            } else {
+                let _ = self
+                    .tail
+                    .compare_exchange(last, next, Ordering::AcqRel, Ordering::Acquire);
+            }
+        }
+    }
+    // None if the queue is empty
+    pub fn deq(&self) -> Option<T> {
+        let guard = self.collector.pin();
+        loop {
+            let first = self.head.load(Ordering::Acquire);
+            let last = self.tail.load(Ordering::Acquire);
+            let next = unsafe { &*first }.next.load(Ordering::Acquire);
+            if first != self.head.load(Ordering::Acquire) {
+                continue;
+            }
+            if first == last {
+                if next.is_null() {
+                    return None;
+                }
+                let _ = self
+                    .tail
+                    .compare_exchange(last, next, Ordering::AcqRel, Ordering::Acquire);
+            } else if self
+                .head
+                .compare_exchange(first, next, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                let a = unsafe { (*(*next).value.get()).take() };
+                unsafe { guard.defer_destroy(first) };
+                return a;
+            }
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        let _guard = self.collector.pin();
+        let first = self.head.load(Ordering::Acquire);
+        unsafe { &*first }.next.load(Ordering::Acquire).is_null()
+    }
+}
+impl<T> Default for LockFreeQueue<T> {
+    fn default() -> LockFreeQueue<T> {
+        LockFreeQueue::new()
+    }
+}
+impl<T> Drop for LockFreeQueue<T> {
+    fn drop(&mut self) {
+        let mut node = *self.head.get_mut();
+        while !node.is_null() {
+            let b = unsafe { Box::from_raw(node) };
+            node = b.next.load(Ordering::Relaxed);
+        }
+    }
+}
+
+// same shape as bounded_queue, but consumers spin on an empty queue
+#[test]
+fn lock_free_queue() {
+    use std::{sync::Arc, thread};
+    let producers = 3;
+    let items = 3000;
+    let q = Arc::new(LockFreeQueue::new());
+    let mut jhs = vec![];
+    for p in 0..producers {
+        let q = q.clone();
+        jhs.push(thread::spawn(move || {
+            for i in 0..items {
+                q.enq((p, i));
+            }
+        }));
+    }
+    let consumers: Vec<_> = (0..3)
+        .map(|_| {
+            let q = q.clone();
+            thread::spawn(move || {
+                let mut last = vec![None; producers];
+                let mut seen = 0;
+                let mut taken = 0;
+                while taken < items {
+                    match q.deq() {
+                        Some((p, i)) => {
+                            assert!(last[p] < Some(i));
+                            last[p] = Some(i);
+                            seen += i;
+                            taken += 1;
+                        }
+                        None => thread::yield_now(),
+                    }
+                }
+                seen
+            })
+        })
+        .collect();
+    for jh in jhs {
+        jh.join().unwrap()
+    }
+    let seen: usize = consumers.into_iter().map(|jh| jh.join().unwrap()).sum();
+    assert_eq!(seen, producers * items * (items - 1) / 2);
+    assert!(q.is_empty());
+    assert_eq!(q.deq(), None);
+}
+
+// every value is dropped exactly once, whether it was dequeued or still queued at the end
+#[test]
+fn lock_free_queue_reclaim() {
+    use super::epoch::Counted;
+    use std::{sync::Arc, thread};
+    let dropped = Arc::new(AtomicUsize::new(0));
+    let q = Arc::new(LockFreeQueue::new());
+    let jhs: Vec<_> = (0..4)
+        .map(|_| {
+            let q = q.clone();
+            let d = dropped.clone();
+            thread::spawn(move || {
+                for i in 0..2000 {
+                    q.enq(Counted(d.clone()));
+                    if i % 3 != 0 {
+                        q.deq();
+                    }
+                }
+            })
+        })
+        .collect();
+    for jh in jhs {
+        jh.join().unwrap()
+    }
+    assert!(dropped.load(Ordering::SeqCst) > 0);
+    drop(q);
+    assert_eq!(dropped.load(Ordering::SeqCst), 4 * 2000);
+}
+
+// synchronous queue as a monitor. an enqueuer waits for its turn, leaves its item and waits
+// until a dequeuer has taken it, so nothing is ever buffered.
+// enqueuing keeps the next enqueuer out until the handoff is over
+pub struct SynchronousQueue<T> {
+    lock: MCSLock<Handoff<T>>,
+    condition: Condition,
+}
+struct Handoff<T> {
+    item: Option<T>,
+    enqueuing: bool,
+}
+unsafe impl<T: Send> Send for SynchronousQueue<T> {}
+unsafe impl<T: Send> Sync for SynchronousQueue<T> {}
+
+impl<T> SynchronousQueue<T> {
+    pub fn new() -> SynchronousQueue<T> {
+        SynchronousQueue {
+            lock: MCSLock::new(Handoff {
+                item: None,
+                enqueuing: false,
+            }),
+            condition: Condition::new(),
+        }
+    }
+    pub fn enq(&self, a: T) {
+        let mut handoff = self.lock.lock_any();
+        self.condition.wait_while(&mut handoff, |h| h.enqueuing);
+        handoff.enqueuing = true;
+        handoff.item = Some(a);
+        self.condition.notify_all();
+        self.condition
+            .wait_while(&mut handoff, |h| h.item.is_some());
+        handoff.enqueuing = false;
+        self.condition.notify_all()
+    }
+    pub fn deq(&self) -> T {
+        let mut handoff = self.lock.lock_any();
+        self.condition
+            .wait_while(&mut handoff, |h| h.item.is_none());
+        let a = handoff.item.take().unwrap();
+        self.condition.notify_all();
+        a
+    }
+}
+impl<T> Default for SynchronousQueue<T> {
+    fn default() -> SynchronousQueue<T> {
+        SynchronousQueue::new()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum NodeType {
+    Item,
+    Reservation,
+}
+
+// lock-free dual queue. the list only ever holds nodes of one kind: items waiting for
+// a dequeuer or reservations waiting for an enqueuer. a caller that finds the other kind at
+// the front fulfills that node by a cas on its item, otherwise it appends its own node and
+// spins until somebody fulfills it.
+// item points to a boxed value, whoever swaps it out of an item node or into a reservation
+// hands over ownership. a fulfilled reservation keeps its pointer so nobody can fill it twice
+pub struct SynchronousDualQueue<T> {
+    head: AtomicPtr<DualNode<T>>,
+    tail: AtomicPtr<DualNode<T>>,
+    collector: Collector,
+}
+struct DualNode<T> {
+    kind: NodeType,
+    item: AtomicPtr<T>,
+    next: AtomicPtr<DualNode<T>>,
+}
+unsafe impl<T: Send> Send for SynchronousDualQueue<T> {}
+unsafe impl<T: Send> Sync for SynchronousDualQueue<T> {}
+
+impl<T> DualNode<T> {
+    fn alloc(kind: NodeType, item: *mut T) -> *mut DualNode<T> {
+        Box::into_raw(Box::new(DualNode {
+            kind,
+            item: AtomicPtr::new(item),
+            next: AtomicPtr::new(std::ptr::null_mut()),
+        }))
+    }
+}
+
+impl<T> SynchronousDualQueue<T> {
+    pub fn new() -> SynchronousDualQueue<T> {
+        let sentinel = DualNode::alloc(NodeType::Item, std::ptr::null_mut());
+        SynchronousDualQueue {
+            head: AtomicPtr::new(sentinel),
+            tail: AtomicPtr::new(sentinel),
+            collector: Collector::new(),
+        }
+    }
+    // the sentinel moves on to the node that just got its partner
+    fn advance_head(&self, head: *mut DualNode<T>, next: *mut DualNode<T>, guard: &Guard) {
+        if self
+            .head
+            .compare_exchange(head, next, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            unsafe { guard.defer_destroy(head) }
+        }
+    }
+    // appends offer behind tail if the queue is empty or holds the same kind.
+    // Some(true) once appended, Some(false) to retry, None if the front has to be fulfilled
+    fn append(&self, offer: *mut DualNode<T>, kind: NodeType) -> Option<bool> {
+        let t = self.tail.load(Ordering::Acquire);
+        let h = self.head.load(Ordering::Acquire);
+        let last = unsafe { &*t };
+        if h != t && last.kind != kind {
+            return None;
+        }
+        let n = last.next.load(Ordering::Acquire);
+        if t != self.tail.load(Ordering::Acquire) {
+            return Some(false);
+        }
+        if !n.is_null() {
+            let _ = self
+                .tail
+                .compare_exchange(t, n, Ordering::AcqRel, Ordering::Acquire);
+            return Some(false);
+        }
+        if last
+            .next
+            .compare_exchange(n, offer, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            return Some(false);
+        }
+        let _ = self
+            .tail
+            .compare_exchange(t, offer, Ordering::AcqRel, Ordering::Acquire);
+        Some(true)
+    }
+    // waits for offer to be fulfilled and helps its way to the front. we stay pinned while
+    // spinning, so offer stays valid even if others already moved head past it
+    fn wait(
+        &self,
+        offer: *mut DualNode<T>,
+        until: impl Fn(*mut T) -> bool,
+        guard: &Guard,
+    ) -> *mut T {
+        let node = unsafe { &*offer };
+        let mut item = node.item.load(Ordering::Acquire);
+        while !until(item) {
+            thread::yield_now();
+            item = node.item.load(Ordering::Acquire);
+        }
+        let h = self.head.load(Ordering::Acquire);
+        if offer == unsafe { &*h }.next.load(Ordering::Acquire) {
+            self.advance_head(h, offer, guard)
+        }
+        item
+    }
+    // the first node behind head if head and tail didn't move while we looked
+    fn front(&self) -> Option<(*mut DualNode<T>, &DualNode<T>)> {
+        let t = self.tail.load(Ordering::Acquire);
+        let h = self.head.load(Ordering::Acquire);
+        let n = unsafe { &*h }.next.load(Ordering::Acquire);
+        if t != self.tail.load(Ordering::Acquire)
+            || h != self.head.load(Ordering::Acquire)
+            || n.is_null()
+        {
+            return None;
+        }
+        Some((h, unsafe { &*n }))
+    }
+    pub fn enq(&self, a: T) {
+        let guard = self.collector.pin();
+        let item = Box::into_raw(Box::new(a));
+        let offer = DualNode::alloc(NodeType::Item, item);
+        loop {
+            match self.append(offer, NodeType::Item) {
+                Some(true) => {
+                    self.wait(offer, |i| i != item, &guard);
+                    return;
+                }
+                Some(false) => continue,
+                None => (),
+            }
+            let (h, n) = match self.front() {
+                Some(front) => front,
+                None => continue,
+            };
+            // the queue turned into items since we looked at the tail
+            if n.kind != NodeType::Reservation {
+                continue;
+            }
+            let fulfilled = n
+                .item
+                .compare_exchange(
+                    std::ptr::null_mut(),
+                    item,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                )
+                .is_ok();
+            self.advance_head(h, n as *const _ as *mut _, &guard);
+            if fulfilled {
+                // offer was never published
+                unsafe { drop(Box::from_raw(offer)) };
+                return;
+            }
+        }
+    }
+    pub fn deq(&self) -> T {
+        let guard = self.collector.pin();
+        let offer = DualNode::alloc(NodeType::Reservation, std::ptr::null_mut());
+        loop {
+            match self.append(offer, NodeType::Reservation) {
+                Some(true) => {
+                    let item = self.wait(offer, |i| !i.is_null(), &guard);
+                    return *unsafe { Box::from_raw(item) };
+                }
+                Some(false) => continue,
+                None => (),
+            }
+            let (h, n) = match self.front() {
+                Some(front) => front,
+                None => continue,
+            };
+            if n.kind != NodeType::Item {
+                continue;
+            }
+            let item = n.item.load(Ordering::Acquire);
+            let taken = !item.is_null()
+                && n.item
+                    .compare_exchange(
+                        item,
+                        std::ptr::null_mut(),
+                        Ordering::AcqRel,
+                        Ordering::Acquire,
+                    )
+                    .is_ok();
+            self.advance_head(h, n as *const _ as *mut _, &guard);
+            if taken {
+                unsafe { drop(Box::from_raw(offer)) };
+                return *unsafe { Box::from_raw(item) };
+            }
+        }
+    }
+}
+impl<T> Default for SynchronousDualQueue<T> {
+    fn default() -> SynchronousDualQueue<T> {
+        SynchronousDualQueue::new()
+    }
+}
+// every enq and deq returned, so all items were handed over and the nodes hold none
+impl<T> Drop for SynchronousDualQueue<T> {
+    fn drop(&mut self) {
+        let mut node = *self.head.get_mut();
+        while !node.is_null() {
+            let b = unsafe { Box::from_raw(node) };
+            node = b.next.load(Ordering::Relaxed);
+        }
+    }
+}
+
+// an enq doesn't return before somebody took its item, and many pairs all find a partner
+fn handoff_test<Q>(q: Q, enq: fn(&Q, usize), deq: fn(&Q) -> usize)
+where
+    Q: Send + Sync + 'static,
+{
+    use std::{sync::atomic::AtomicBool, sync::Arc, time::Duration};
+    let q = Arc::new(q);
+    let done = Arc::new(AtomicBool::new(false));
+    let (q1, d1) = (q.clone(), done.clone());
+    let producer = thread::spawn(move || {
+        enq(&q1, 42);
+        d1.store(true, Ordering::SeqCst)
+    });
+    thread::sleep(Duration::from_millis(20));
+    assert!(!done.load(Ordering::SeqCst));
+    assert_eq!(deq(&q), 42);
+    producer.join().unwrap();
+    assert!(done.load(Ordering::SeqCst));
+
+    let pairs = 4;
+    let items = 300;
+    let mut jhs = vec![];
+    for p in 0..pairs {
+        let q = q.clone();
+        jhs.push(thread::spawn(move || {
+            for i in 0..items {
+                enq(&q, p * items + i);
+            }
+            0
+        }));
+    }
+    for _ in 0..pairs {
+        let q = q.clone();
+        jhs.push(thread::spawn(move || (0..items).map(|_| deq(&q)).sum()));
+    }
+    let sum: usize = jhs.into_iter().map(|jh| jh.join().unwrap()).sum();
+    let n = pairs * items;
+    assert_eq!(sum, n * (n - 1) / 2);
+}
+
+#[test]
+fn synchronous_queue() {
+    handoff_test(SynchronousQueue::new(), |q, a| q.enq(a), |q| q.deq());
+}
+
+#[test]
+fn synchronous_dual_queue() {
+    handoff_test(SynchronousDualQueue::new(), |q, a| q.enq(a), |q| q.deq());
+}
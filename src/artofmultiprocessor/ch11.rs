@@ -0,0 +1,489 @@
+use super::ch7::Backoff;
+use super::epoch::{Collector, Guard};
+use super::tagged::{AtomicStampedPtr, StampedCasError};
+use rand::Rng;
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::{
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+// the stack interface shared by the ch11 stacks, pop is None on an empty stack
+pub trait ConcurrentStack<T> {
+    fn push(&self, a: T);
+    fn pop(&self) -> Option<T>;
+}
+
+// the value is moved out by the thread that pops the node, the node itself goes to the collector
+struct Node<T> {
+    value: ManuallyDrop<T>,
+    next: *mut Node<T>,
+}
+impl<T> Node<T> {
+    fn alloc(value: T) -> *mut Node<T> {
+        Box::into_raw(Box::new(Node {
+            value: ManuallyDrop::new(value),
+            next: std::ptr::null_mut(),
+        }))
+    }
+    // node is ours and was never published
+    unsafe fn into_value(node: *mut Node<T>) -> T {
+        let mut b = Box::from_raw(node);
+        ManuallyDrop::take(&mut b.value)
+    }
+}
+
+// treiber stack, push and pop are a single cas on top. a failed cas means contention,
+// so the caller backs off before trying again. a pop stays pinned from reading top to its cas,
+// so that node can't be freed and come back at the same address in between (aba)
+pub struct LockFreeStack<T> {
+    top: AtomicPtr<Node<T>>,
+    collector: Collector,
+}
+unsafe impl<T: Send> Send for LockFreeStack<T> {}
+unsafe impl<T: Send> Sync for LockFreeStack<T> {}
+
+impl<T> LockFreeStack<T> {
+    pub fn new() -> LockFreeStack<T> {
+        LockFreeStack {
+            top: AtomicPtr::new(std::ptr::null_mut()),
+            collector: Collector::new(),
+        }
+    }
+    fn try_push(&self, node: *mut Node<T>) -> bool {
+        let top = self.top.load(Ordering::Acquire);
+        unsafe { (*node).next = top };
+        self.top
+            .compare_exchange(top, node, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    }
+    // Err if the cas lost, Ok(None) if the stack is empty
+    fn try_pop(&self, guard: &Guard) -> Result<Option<T>, ()> {
+        let top = self.top.load(Ordering::Acquire);
+        if top.is_null() {
+            return Ok(None);
+        }
+        let next = unsafe { (*top).next };
+        if self
+            .top
+            .compare_exchange(top, next, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            return Err(());
+        }
+        let a = unsafe { std::ptr::read(&*(*top).value) };
+        unsafe { guard.defer_destroy(top) };
+        Ok(Some(a))
+    }
+    pub fn push(&self, a: T) {
+        let node = Node::alloc(a);
+        let mut bo = Backoff::new(1, 100);
+        while !self.try_push(node) {
+            thread::sleep(Duration::from_micros(bo.next()))
+        }
+    }
+    pub fn pop(&self) -> Option<T> {
+        let guard = self.collector.pin();
+        let mut bo = Backoff::new(1, 100);
+        loop {
+            if let Ok(a) = self.try_pop(&guard) {
+                return a;
+            }
+            thread::sleep(Duration::from_micros(bo.next()))
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.top.load(Ordering::Acquire).is_null()
+    }
+}
+impl<T> ConcurrentStack<T> for LockFreeStack<T> {
+    fn push(&self, a: T) {
+        LockFreeStack::push(self, a)
+    }
+    fn pop(&self) -> Option<T> {
+        LockFreeStack::pop(self)
+    }
+}
+impl<T> Default for LockFreeStack<T> {
+    fn default() -> LockFreeStack<T> {
+        LockFreeStack::new()
+    }
+}
+impl<T> Drop for LockFreeStack<T> {
+    fn drop(&mut self) {
+        let mut node = *self.top.get_mut();
+        while !node.is_null() {
+            let mut b = unsafe { Box::from_raw(node) };
+            unsafe { ManuallyDrop::drop(&mut b.value) };
+            node = b.next;
+        }
+    }
+}
+
+// exchanger slot states, kept in the stamp next to the offered pointer
+const EMPTY: u16 = 0;
+const WAITING: u16 = 1;
+const BUSY: u16 = 2;
+
+// lock-free exchanger. the first thread moves the slot from EMPTY to WAITING with its offer
+// and spins, the second one swaps in its own offer and BUSY, then the first takes it and
+// empties the slot. a waiter that runs out of time takes its offer back, unless it lost that
+// race to a partner. offers are raw pointers and whoever gets one owns it
+pub struct Exchanger<T> {
+    slot: AtomicStampedPtr<T>,
+    phantom: PhantomData<T>,
+}
+unsafe impl<T: Send> Send for Exchanger<T> {}
+unsafe impl<T: Send> Sync for Exchanger<T> {}
+
+impl<T> Exchanger<T> {
+    pub fn new() -> Exchanger<T> {
+        Exchanger {
+            slot: AtomicStampedPtr::new(std::ptr::null_mut(), EMPTY).unwrap(),
+            phantom: PhantomData,
+        }
+    }
+    // Ok with the partner's value, or Err with our own if nobody showed up within timeout
+    pub fn exchange(&self, a: T, timeout: Duration) -> Result<T, T> {
+        let mine = Box::into_raw(Box::new(a));
+        match self.offer(mine, timeout, |_| true) {
+            Ok(yours) => Ok(*unsafe { Box::from_raw(yours) }),
+            Err(()) => Err(*unsafe { Box::from_raw(mine) }),
+        }
+    }
+    // swaps mine for the offer of a partner that accept() agrees to meet,
+    // Err if nobody came in time or mine doesn't fit in the slot
+    fn offer(
+        &self,
+        mine: *mut T,
+        timeout: Duration,
+        accept: impl Fn(*mut T) -> bool,
+    ) -> Result<*mut T, ()> {
+        if !AtomicStampedPtr::fits(mine) {
+            return Err(());
+        }
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            let (yours, state) = self.slot.load(Ordering::Acquire);
+            match state {
+                EMPTY => {
+                    if self
+                        .slot
+                        .compare_exchange(
+                            (yours, EMPTY),
+                            (mine, WAITING),
+                            Ordering::AcqRel,
+                            Ordering::Acquire,
+                        )
+                        .is_err()
+                    {
+                        continue;
+                    }
+                    while Instant::now() < deadline {
+                        let (yours, state) = self.slot.load(Ordering::Acquire);
+                        if state == BUSY {
+                            self.clear();
+                            return Ok(yours);
+                        }
+                        thread::yield_now()
+                    }
+                    return match self.slot.compare_exchange(
+                        (mine, WAITING),
+                        (std::ptr::null_mut(), EMPTY),
+                        Ordering::AcqRel,
+                        Ordering::Acquire,
+                    ) {
+                        Ok(_) => Err(()),
+                        // a partner got in just before we gave up
+                        Err(StampedCasError::Changed(yours, _)) => {
+                            self.clear();
+                            Ok(yours)
+                        }
+                        Err(StampedCasError::TooWide(_)) => unreachable!("null always fits"),
+                    };
+                }
+                WAITING if accept(yours) => {
+                    if self
+                        .slot
+                        .compare_exchange(
+                            (yours, WAITING),
+                            (mine, BUSY),
+                            Ordering::AcqRel,
+                            Ordering::Acquire,
+                        )
+                        .is_ok()
+                    {
+                        return Ok(yours);
+                    }
+                }
+                _ => thread::yield_now(),
+            }
+        }
+        Err(())
+    }
+    fn clear(&self) {
+        // null always fits
+        let _ = self
+            .slot
+            .store(std::ptr::null_mut(), EMPTY, Ordering::Release);
+    }
+}
+
+impl<T> Default for Exchanger<T> {
+    fn default() -> Exchanger<T> {
+        Exchanger::new()
+    }
+}
+
+#[test]
+fn exchanger() {
+    let e = Arc::new(Exchanger::new());
+    assert_eq!(e.exchange("alone", Duration::from_millis(5)), Err("alone"));
+    let e1 = e.clone();
+    let partner = thread::spawn(move || e1.exchange("b", Duration::from_secs(10)));
+    assert_eq!(e.exchange("a", Duration::from_secs(10)), Ok("b"));
+    assert_eq!(partner.join().unwrap(), Ok("a"));
+}
+
+// an odd number of threads with short timeouts, so some of them always go home alone.
+// whoever got a value has to be the one who got ours, and a timeout hands back our own
+#[test]
+fn exchanger_pairs() {
+    use std::collections::HashMap;
+    let threads = 5;
+    let rounds = 300;
+    let e = Arc::new(Exchanger::new());
+    let jhs: Vec<_> = (0..threads)
+        .map(|t| {
+            let e = e.clone();
+            thread::spawn(move || {
+                let mut swaps = vec![];
+                for r in 0..rounds {
+                    let mine = t * rounds + r;
+                    match e.exchange(mine, Duration::from_micros(500)) {
+                        Ok(yours) => swaps.push((mine, yours)),
+                        Err(back) => assert_eq!(back, mine),
+                    }
+                }
+                swaps
+            })
+        })
+        .collect();
+    let swaps: HashMap<usize, usize> = jhs.into_iter().flat_map(|jh| jh.join().unwrap()).collect();
+    for (mine, yours) in &swaps {
+        assert_ne!(mine / rounds, yours / rounds);
+        assert_eq!(swaps.get(yours), Some(mine));
+    }
+    println!("{} of {} offers exchanged", swaps.len(), threads * rounds);
+}
+
+// how long a thread waits in the elimination array for a partner
+const ELIMINATION_WAIT: Duration = Duration::from_micros(200);
+
+// treiber stack that backs off into an array of exchangers. a push offers its node, a pop
+// offers null, and a push and pop that meet cancel out without ever touching top.
+// a waiting offer is only taken by the other kind, two pushes never swap values.
+// range is how many exchangers there are to spread over
+pub struct EliminationBackoffStack<T> {
+    stack: LockFreeStack<T>,
+    exchangers: Vec<Exchanger<Node<T>>>,
+    eliminated: AtomicUsize,
+}
+unsafe impl<T: Send> Send for EliminationBackoffStack<T> {}
+unsafe impl<T: Send> Sync for EliminationBackoffStack<T> {}
+
+impl<T> EliminationBackoffStack<T> {
+    pub fn new(range: usize) -> EliminationBackoffStack<T> {
+        assert!(
+            range > 0,
+            "EliminationBackoffStack needs at least one exchanger"
+        );
+        EliminationBackoffStack {
+            stack: LockFreeStack::new(),
+            exchangers: (0..range).map(|_| Exchanger::new()).collect(),
+            eliminated: AtomicUsize::new(0),
+        }
+    }
+    fn visit(
+        &self,
+        mine: *mut Node<T>,
+        accept: impl Fn(*mut Node<T>) -> bool,
+    ) -> Result<*mut Node<T>, ()> {
+        let slot = rand::thread_rng().gen_range(0, self.exchangers.len());
+        self.exchangers[slot].offer(mine, ELIMINATION_WAIT, accept)
+    }
+    pub fn push(&self, a: T) {
+        let node = Node::alloc(a);
+        while !self.stack.try_push(node) {
+            // the partner is a pop that took our node
+            if self.visit(node, |yours| yours.is_null()).is_ok() {
+                return;
+            }
+        }
+    }
+    pub fn pop(&self) -> Option<T> {
+        let guard = self.stack.collector.pin();
+        loop {
+            if let Ok(a) = self.stack.try_pop(&guard) {
+                return a;
+            }
+            if let Ok(node) = self.visit(std::ptr::null_mut(), |yours| !yours.is_null()) {
+                self.eliminated.fetch_add(1, Ordering::Relaxed);
+                return Some(unsafe { Node::into_value(node) });
+            }
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.stack.is_empty()
+    }
+    pub fn range(&self) -> usize {
+        self.exchangers.len()
+    }
+    // push and pop pairs that met in the exchangers
+    pub fn eliminated(&self) -> usize {
+        self.eliminated.load(Ordering::Relaxed)
+    }
+}
+impl<T> ConcurrentStack<T> for EliminationBackoffStack<T> {
+    fn push(&self, a: T) {
+        EliminationBackoffStack::push(self, a)
+    }
+    fn pop(&self) -> Option<T> {
+        EliminationBackoffStack::pop(self)
+    }
+}
+
+// every thread pushes and pops half of the time
+pub fn stack_bench<S>(stack: Arc<S>, threads: usize, ops: usize) -> Duration
+where
+    S: ConcurrentStack<usize> + Send + Sync + 'static,
+{
+    let now = Instant::now();
+    let jhs: Vec<_> = (0..threads)
+        .map(|_| {
+            let s = stack.clone();
+            thread::spawn(move || {
+                let mut rng = rand::thread_rng();
+                for i in 0..ops {
+                    if rng.gen_range(0, 2) == 0 {
+                        s.push(i);
+                    } else {
+                        s.pop();
+                    }
+                }
+            })
+        })
+        .collect();
+    for jh in jhs {
+        jh.join().unwrap()
+    }
+    now.elapsed()
+}
+
+// lifo from one thread, then every thread pushes its own values and pops as many,
+// whatever is left over is drained and nothing may be lost or popped twice
+fn stack_test<S>(stack: S)
+where
+    S: ConcurrentStack<usize> + Send + Sync + 'static,
+{
+    assert_eq!(stack.pop(), None);
+    for i in 0..10 {
+        stack.push(i);
+    }
+    assert!((0..10).rev().all(|i| stack.pop() == Some(i)));
+    assert_eq!(stack.pop(), None);
+
+    let threads = 4;
+    let items = 2000;
+    let stack = Arc::new(stack);
+    let jhs: Vec<_> = (0..threads)
+        .map(|t| {
+            let s = stack.clone();
+            thread::spawn(move || {
+                let mut popped = vec![];
+                for i in 0..items {
+                    s.push(t * items + i);
+                    if i % 2 == 1 {
+                        popped.extend(s.pop());
+                        popped.extend(s.pop());
+                    }
+                }
+                popped
+            })
+        })
+        .collect();
+    let mut popped: Vec<usize> = jhs.into_iter().flat_map(|jh| jh.join().unwrap()).collect();
+    while let Some(a) = stack.pop() {
+        popped.push(a)
+    }
+    popped.sort_unstable();
+    assert_eq!(popped, (0..threads * items).collect::<Vec<_>>());
+}
+
+#[test]
+fn treiber() {
+    stack_test(LockFreeStack::new());
+}
+
+#[test]
+fn elimination() {
+    stack_test(EliminationBackoffStack::new(4));
+}
+
+// a push and a pop that only go through the exchangers still meet
+#[test]
+fn elimination_pairs() {
+    let s = Arc::new(EliminationBackoffStack::new(1));
+    let s1 = s.clone();
+    let popper = thread::spawn(move || {
+        (0..100)
+            .map(|_| loop {
+                if let Ok(node) = s1.visit(std::ptr::null_mut(), |yours| !yours.is_null()) {
+                    break unsafe { Node::into_value(node) };
+                }
+            })
+            .sum::<usize>()
+    });
+    for i in 0..100 {
+        let node = Node::alloc(i);
+        while s.visit(node, |yours| yours.is_null()).is_err() {}
+    }
+    assert_eq!(popper.join().unwrap(), 99 * 100 / 2);
+    assert!(s.is_empty());
+}
+
+// values left on the stack are dropped with it, popped ones exactly once
+#[test]
+fn stack_drop() {
+    use super::epoch::Counted;
+    let dropped = Arc::new(AtomicUsize::new(0));
+    let s = EliminationBackoffStack::new(2);
+    for _ in 0..10 {
+        s.push(Counted(dropped.clone()));
+    }
+    drop(s.pop());
+    assert_eq!(dropped.load(Ordering::SeqCst), 1);
+    drop(s);
+    assert_eq!(dropped.load(Ordering::SeqCst), 10);
+}
+
+#[test]
+fn stack_comparison() {
+    println!(
+        "treiber {:?}",
+        stack_bench(Arc::new(LockFreeStack::new()), 4, 5000)
+    );
+    for range in &[1, 2, 4, 8] {
+        let s = Arc::new(EliminationBackoffStack::new(*range));
+        let time = stack_bench(s.clone(), 4, 5000);
+        println!(
+            "elimination range {} {:?} ({} eliminated)",
+            range,
+            time,
+            s.eliminated()
+        );
+    }
+}
@@ -1,59 +1,288 @@
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::{
-    cell::{Cell, UnsafeCell},
-    sync::Arc,
+    cell::UnsafeCell,
+    marker::PhantomData,
+    ops::Deref,
+    ops::DerefMut,
+    ptr,
+    sync::{Arc, Barrier},
     thread,
 };
-use thread::JoinHandle;
-#[allow(dead_code)]
-pub fn spawn<F>(f: F) -> thread::JoinHandle<()>
+
+// hands out thread ids in 0..capacity, an id can be taken again once its ThreadId is dropped.
+// the id based locks (Peterson, FilterLock, Bakery, ...) own one sized to their capacity
+pub struct ThreadIds {
+    used: Vec<AtomicBool>,
+}
+pub struct ThreadId<'a> {
+    ids: &'a ThreadIds,
+    id: usize,
+    // an id belongs to the thread that registered it
+    phantom: PhantomData<*const ()>,
+}
+impl ThreadIds {
+    pub fn new(capacity: usize) -> ThreadIds {
+        ThreadIds {
+            used: (0..capacity).map(|_| AtomicBool::new(false)).collect(),
+        }
+    }
+    pub fn capacity(&self) -> usize {
+        self.used.len()
+    }
+    pub fn try_register(&self) -> Option<ThreadId<'_>> {
+        self.used.iter().enumerate().find_map(|(id, used)| {
+            used.compare_exchange(false, true, Ordering::AcqRel, Ordering::Relaxed)
+                .ok()
+                .map(|_| ThreadId {
+                    ids: self,
+                    id,
+                    phantom: PhantomData,
+                })
+        })
+    }
+    // waits until some other thread gives its id back
+    pub fn register(&self) -> ThreadId<'_> {
+        loop {
+            if let Some(id) = self.try_register() {
+                return id;
+            }
+            thread::yield_now()
+        }
+    }
+}
+impl<'a> ThreadId<'a> {
+    pub fn get(&self) -> usize {
+        self.id
+    }
+}
+impl<'a> Drop for ThreadId<'a> {
+    fn drop(&mut self) {
+        self.ids.used[self.id].store(false, Ordering::Release)
+    }
+}
+
+// the spawned thread holds an id for lock until it exits. it comes from the lock's own ThreadIds,
+// or from spare for the locks any thread can take
+pub fn spawn<T, L, F>(lock: &Arc<L>, spare: &Arc<ThreadIds>, f: F) -> thread::JoinHandle<()>
 where
-    F: Fn(usize) + 'static + Send,
+    L: Lock<T> + Send + Sync + 'static,
+    F: FnOnce(&L, &mut ThreadId<'_>) + 'static + Send,
 {
-    static mut COUNTER: usize = 0;
-    unsafe {
-        let c = COUNTER;
+    let (lock, spare) = (lock.clone(), spare.clone());
+    thread::spawn(move || {
+        let mut id = lock.ids().unwrap_or(&spare).register();
+        f(&lock, &mut id)
+    })
+}
+
+#[test]
+fn thread_ids() {
+    let ids = ThreadIds::new(2);
+    let a = ids.register();
+    let b = ids.register();
+    assert_eq!((a.get(), b.get()), (0, 1));
+    assert!(ids.try_register().is_none());
+    std::mem::drop(a);
+    assert_eq!(ids.register().get(), 0);
+}
 
-        COUNTER += 1;
-        thread::spawn(move || f(c))
+/// Common interface of the mutual exclusion algorithms in ch2 and ch7.
+/// acquire/release is the raw protocol, lock wraps it in a guard that releases on drop.
+/// tid is only used by the algorithms that need thread ids, the others ignore it.
+/// The id based ones own a ThreadIds sized to their capacity and lock only takes ids from it.
+///
+/// # Safety
+///
+/// If ids returns None, acquire has to exclude every other holder whatever tid the callers pass.
+/// Otherwise it has to exclude every other holder as long as the ids are unique ones of that
+/// ThreadIds.
+pub unsafe trait Lock<T> {
+    // whatever acquire needs to hand over to release (thread id, queue slot, ...)
+    type Token;
+    // tid has to be below the lock's capacity, no other thread may use the same tid with this lock
+    // while we acquire or hold it, and the calling thread must not hold the lock already
+    unsafe fn acquire(&self, tid: usize) -> Self::Token;
+    // must only be called with a token returned by acquire on the same lock
+    unsafe fn release(&self, token: Self::Token);
+    fn value(&self) -> &UnsafeCell<T>;
+    // where the ids for lock have to come from, None if any thread can take the lock
+    fn ids(&self) -> Option<&ThreadIds> {
+        None
+    }
+
+    // the id stays borrowed until the guard is gone, so no one else can lock with it meanwhile
+    fn lock<'a>(&'a self, id: &'a mut ThreadId<'_>) -> LockGuard<'a, T, Self>
+    where
+        Self: Sized,
+    {
+        if let Some(ids) = self.ids() {
+            assert!(ptr::eq(id.ids, ids), "thread id from another ThreadIds");
+        }
+        unsafe {
+            let token = self.acquire(id.get());
+            LockGuard::new(self, id.get(), token)
+        }
     }
 }
 
-//trait Lock {
-//  type Item;
-//  type Guard;
-//  fn new(a: Self::Item) -> Self;
-//  fn lock(&self, tid: usize) -> Self::Guard;
-//  fn unlock(&self, tid: usize);
-//  fn get(g: &Self::Guard) -> &mut Self::Item;
-//}
+/// Locks that ignore tid, or only use it for something that doesn't matter for exclusion.
+/// Any thread can take them, so they can be locked without a ThreadId.
+///
+/// # Safety
+///
+/// acquire has to exclude every other holder whatever tid the callers pass, 0 included.
+pub unsafe trait AnyTidLock<T>: Lock<T> {
+    fn lock_any(&self) -> LockGuard<'_, T, Self>
+    where
+        Self: Sized,
+    {
+        unsafe {
+            let token = self.acquire(0);
+            LockGuard::new(self, 0, token)
+        }
+    }
+}
+
+pub struct LockGuard<'a, T, L: Lock<T>> {
+    lock: &'a L,
+    tid: usize,
+    token: Option<L::Token>,
+    phantom: PhantomData<&'a mut T>,
+}
+impl<'a, T, L: Lock<T>> LockGuard<'a, T, L> {
+    // for locks that acquire in other ways than Lock::acquire (try_lock, timeouts),
+    // the token has to mean the lock is held. tid is used when the lock is taken again in unlocked,
+    // so it has to stay ours under the rules of Lock::acquire for as long as the guard lives
+    pub unsafe fn new(lock: &'a L, tid: usize, token: L::Token) -> LockGuard<'a, T, L> {
+        LockGuard {
+            lock,
+            tid,
+            token: Some(token),
+            phantom: PhantomData,
+        }
+    }
+    // releases the lock while f runs and takes it again afterwards, condition variables wait in f
+    pub fn unlocked<R>(&mut self, f: impl FnOnce() -> R) -> R {
+        if let Some(token) = self.token.take() {
+            unsafe { self.lock.release(token) }
+        }
+        let r = f();
+        self.token = Some(unsafe { self.lock.acquire(self.tid) });
+        r
+    }
+}
+impl<'a, T, L: Lock<T>> Deref for LockGuard<'a, T, L> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value().get() }
+    }
+}
+impl<'a, T, L: Lock<T>> DerefMut for LockGuard<'a, T, L> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value().get() }
+    }
+}
+impl<'a, T, L: Lock<T>> Drop for LockGuard<'a, T, L> {
+    fn drop(&mut self) {
+        if let Some(token) = self.token.take() {
+            unsafe { self.lock.release(token) }
+        }
+    }
+}
+
+// every thread increments a shared counter iters times, panics if an increment got lost
+pub fn counter_test<L>(lock: L, threads: usize, iters: usize)
+where
+    L: Lock<usize> + Send + Sync + 'static,
+{
+    let lo = Arc::new(lock);
+    let spare = Arc::new(ThreadIds::new(threads));
+    let mut jhs = vec![];
+    for _ in 0..threads {
+        let jh = spawn(&lo, &spare, move |l, id| {
+            for _ in 0..iters {
+                let mut num = l.lock(id);
+                *num += 1;
+            }
+        });
+        jhs.push(jh)
+    }
+
+    for jh in jhs {
+        jh.join().unwrap()
+    }
+    let mut id = lo.ids().unwrap_or(&spare).register();
+    let value = *lo.lock(&mut id);
+    println!("value is {}", value);
+    assert_eq!(value, threads * iters);
+}
+
+// threads take the lock until total acquisitions are done, returns how often each thread got it.
+// they start together, otherwise the first one runs alone until the others are spawned
+pub fn fairness_test<L>(lock: L, threads: usize, total: usize) -> Vec<usize>
+where
+    L: Lock<Vec<usize>> + Send + Sync + 'static,
+{
+    let lo = Arc::new(lock);
+    let spare = Arc::new(ThreadIds::new(threads));
+    let barrier = Arc::new(Barrier::new(threads));
+    let mut jhs = vec![];
+    for _ in 0..threads {
+        let b = barrier.clone();
+        let jh = spawn(&lo, &spare, move |l, id| {
+            let tid = id.get();
+            b.wait();
+            loop {
+                let mut counts = l.lock(id);
+                if counts.iter().sum::<usize>() >= total {
+                    break;
+                }
+                counts[tid] += 1;
+                drop(counts);
+                // give the others a chance to queue up, with few cores the os would
+                // otherwise hand the lock to whoever has the rest of a time slice
+                thread::yield_now()
+            }
+        });
+        jhs.push(jh)
+    }
+
+    for jh in jhs {
+        jh.join().unwrap()
+    }
+    let mut id = lo.ids().unwrap_or(&spare).register();
+    let counts = lo.lock(&mut id).clone();
+    counts
+}
 
 // it deadlocks in concurrent executions
-pub struct LockOne<T>(UnsafeCell<T>, [AtomicBool; 2]);
+pub struct LockOne<T>(UnsafeCell<T>, [AtomicBool; 2], ThreadIds);
 impl<T> LockOne<T> {
     pub fn new(a: T) -> LockOne<T> {
         LockOne(
             UnsafeCell::new(a),
             [AtomicBool::new(false), AtomicBool::new(false)],
+            ThreadIds::new(2),
         )
     }
-    pub fn lock<'a>(&'a self, tid: usize) -> LockGuard<'a, T> {
-        self.1[tid].store(true, Ordering::Release);
-        while self.1[1 - tid].load(Ordering::Acquire) {}
-        LockGuard(&self.0, &self.1, tid)
-    }
 }
-
-pub struct LockGuard<'a, T>(&'a UnsafeCell<T>, &'a [AtomicBool; 2], usize);
-impl<'a, T> LockGuard<'a, T> {
-    pub fn get(&self) -> &mut T {
-        unsafe { &mut *(self.0).get() }
+unsafe impl<T> Lock<T> for LockOne<T> {
+    type Token = usize;
+    unsafe fn acquire(&self, tid: usize) -> usize {
+        self.1[tid].store(true, Ordering::SeqCst);
+        while self.1[1 - tid].load(Ordering::SeqCst) {
+            thread::yield_now()
+        }
+        tid
     }
-}
-impl<'a, T> Drop for LockGuard<'a, T> {
-    fn drop(&mut self) {
-        self.1[self.2].store(false, Ordering::Release);
-        println!("dropped")
+    unsafe fn release(&self, tid: usize) {
+        self.1[tid].store(false, Ordering::SeqCst);
+    }
+    fn value(&self) -> &UnsafeCell<T> {
+        &self.0
+    }
+    fn ids(&self) -> Option<&ThreadIds> {
+        Some(&self.2)
     }
 }
 
@@ -61,90 +290,98 @@ unsafe impl<T> Send for LockOne<T> {}
 unsafe impl<T> Sync for LockOne<T> {}
 
 #[test]
+#[ignore = "LockOne deadlocks once both threads want the lock at the same time"]
 fn test() {
-    let lo = Arc::new(LockOne::new(0));
-    let mut jhs = vec![];
-    for _ in 0..2 {
-        let l = lo.clone();
-        let tid = spawn(move |tid| {
-            for _ in 0..20 {
-                let num = l.lock(tid);
-                *num.get() = *num.get() + 1;
-                println!("{}", tid)
-            }
-        });
-        jhs.push(tid)
-    }
+    counter_test(LockOne::new(0), 2, 20)
+}
 
-    for jh in jhs {
-        jh.join().unwrap()
+// one thread after the other is fine
+#[test]
+fn testlock1_sequential() {
+    let lo = LockOne::new(0);
+    let ids = lo.ids().unwrap();
+    let mut ab = [ids.register(), ids.register()];
+    for id in &mut ab {
+        for _ in 0..20 {
+            *lo.lock(id) += 1;
+        }
     }
-    println!("value is {}", lo.lock(1).get())
+    assert_eq!(*lo.lock(&mut ab[0]), 40);
 }
 
 // it deadlocks in sequential executions
-pub struct LockTwo<T>(UnsafeCell<T>, AtomicUsize);
+pub struct LockTwo<T>(UnsafeCell<T>, AtomicUsize, ThreadIds);
 unsafe impl<T> Send for LockTwo<T> {}
 unsafe impl<T> Sync for LockTwo<T> {}
 
 impl<T> LockTwo<T> {
     pub fn new(a: T) -> LockTwo<T> {
-        LockTwo(UnsafeCell::new(a), AtomicUsize::new(3))
+        LockTwo(UnsafeCell::new(a), AtomicUsize::new(3), ThreadIds::new(2))
     }
-    pub fn lock<'a>(&'a self, tid: usize) -> &mut T {
-        unsafe {
-            self.1.store(tid, Ordering::Release);
-            while self.1.load(Ordering::Acquire) == tid {}
-            let t = &mut *self.0.get();
-            t
+}
+unsafe impl<T> Lock<T> for LockTwo<T> {
+    type Token = ();
+    unsafe fn acquire(&self, tid: usize) {
+        self.1.store(tid, Ordering::SeqCst);
+        while self.1.load(Ordering::SeqCst) == tid {
+            thread::yield_now()
         }
     }
+    // the lock is handed over by the next thread writing itself as victim
+    unsafe fn release(&self, _: ()) {}
+    fn value(&self) -> &UnsafeCell<T> {
+        &self.0
+    }
+    fn ids(&self) -> Option<&ThreadIds> {
+        Some(&self.2)
+    }
 }
 
 #[test]
+#[ignore = "LockTwo deadlocks once the other thread stops locking"]
 pub fn testlock2() {
-    let lo = Arc::new(LockTwo::new(0));
-    let mut jhs = vec![];
-    for _ in 0..2 {
-        let l = lo.clone();
-        let tid: JoinHandle<()> = spawn(move |tid| {
-            for _ in 0..20 {
-                let num = l.lock(tid);
-                *num = *num + 1;
-                println!("{} - {}", tid, num)
-            }
-        });
-        jhs.push(tid)
-    }
-
-    for jh in jhs {
-        jh.join().unwrap()
-    }
+    counter_test(LockTwo::new(0), 2, 20)
 }
 
 pub struct Peterson<T> {
     value: UnsafeCell<T>,
     interested: [AtomicBool; 2],
     victim: AtomicUsize,
+    ids: ThreadIds,
 }
 impl<T> Peterson<T> {
-    fn new(a: T) -> Peterson<T> {
+    pub fn new(a: T) -> Peterson<T> {
         Peterson {
             value: UnsafeCell::new(a),
             interested: [AtomicBool::new(false), AtomicBool::new(false)],
             victim: AtomicUsize::new(3),
+            ids: ThreadIds::new(2),
         }
     }
-    fn lock<'a>(&'a self, me: usize) -> LockGuard<'a, T> {
+}
+unsafe impl<T> Lock<T> for Peterson<T> {
+    type Token = usize;
+    unsafe fn acquire(&self, me: usize) -> usize {
         let other = 1 - me;
 
-        self.interested[me].store(true, Ordering::Relaxed);
-        self.victim.swap(me, Ordering::AcqRel);
+        self.interested[me].store(true, Ordering::SeqCst);
+        self.victim.store(me, Ordering::SeqCst);
 
-        while self.interested[other].load(Ordering::Acquire)
-            && self.victim.load(Ordering::Relaxed) == me
-        {}
-        LockGuard(&self.value, &self.interested, me)
+        while self.interested[other].load(Ordering::SeqCst)
+            && self.victim.load(Ordering::SeqCst) == me
+        {
+            thread::yield_now()
+        }
+        me
+    }
+    unsafe fn release(&self, me: usize) {
+        self.interested[me].store(false, Ordering::SeqCst);
+    }
+    fn value(&self) -> &UnsafeCell<T> {
+        &self.value
+    }
+    fn ids(&self) -> Option<&ThreadIds> {
+        Some(&self.ids)
     }
 }
 
@@ -152,31 +389,43 @@ unsafe impl<T> Send for Peterson<T> {}
 unsafe impl<T> Sync for Peterson<T> {}
 
 #[test]
-fn testPeterson() {
-    let lo = Arc::new(Peterson::new(0));
-    let mut jhs = vec![];
-    for _ in 0..2 {
-        let l = lo.clone();
-        let tid = spawn(move |tid| {
-            for _ in 0..20 {
-                let num = l.lock(tid);
-                *num.get() = *num.get() + 1;
-                println!("{}", tid)
-            }
-        });
-        jhs.push(tid)
-    }
+fn test_peterson() {
+    counter_test(Peterson::new(0), 2, 20)
+}
 
+// more threads than ids, the later ones wait for an id to be given back
+#[test]
+fn test_peterson_reuse() {
+    let lo = Arc::new(Peterson::new(0));
+    let spare = Arc::new(ThreadIds::new(0));
+    let jhs: Vec<_> = (0..6)
+        .map(|_| {
+            spawn(&lo, &spare, move |l, id| {
+                for _ in 0..20 {
+                    *l.lock(id) += 1;
+                }
+            })
+        })
+        .collect();
     for jh in jhs {
         jh.join().unwrap()
     }
-    println!("value is {}", lo.lock(1).get())
+    assert_eq!(*lo.lock(&mut lo.ids.register()), 120);
+}
+
+#[test]
+#[should_panic(expected = "thread id from another ThreadIds")]
+fn test_peterson_foreign_id() {
+    let lo = Peterson::new(0);
+    let other = ThreadIds::new(2);
+    lo.lock(&mut other.register());
 }
 
 pub struct FilterLock<T> {
     value: UnsafeCell<T>,
     levels: Vec<AtomicUsize>,
     victim: Vec<AtomicUsize>,
+    ids: ThreadIds,
 }
 impl<T> FilterLock<T> {
     pub fn new(a: T, n: usize) -> FilterLock<T> {
@@ -190,27 +439,39 @@ impl<T> FilterLock<T> {
 
         FilterLock {
             value: UnsafeCell::new(a),
-            levels: levels,
+            levels,
             victim: victims,
+            ids: ThreadIds::new(n),
         }
     }
-    pub fn lock(&self, me: usize) -> &mut T {
+}
+unsafe impl<T> Lock<T> for FilterLock<T> {
+    type Token = usize;
+    unsafe fn acquire(&self, me: usize) -> usize {
         for i in (0..self.levels.len()).rev() {
-            self.levels[me].store(i, Ordering::Relaxed);
-            self.victim[i].swap(me, Ordering::Relaxed);
+            self.levels[me].store(i, Ordering::SeqCst);
+            self.victim[i].store(me, Ordering::SeqCst);
 
             while self
                 .levels
                 .iter()
                 .enumerate()
-                .any(|(t, x)| t != me && x.load(Ordering::Relaxed) <= i)
-                && self.victim[i].load(Ordering::Relaxed) == me
-            {}
+                .any(|(t, x)| t != me && x.load(Ordering::SeqCst) <= i)
+                && self.victim[i].load(Ordering::SeqCst) == me
+            {
+                thread::yield_now()
+            }
         }
-        unsafe { &mut *self.value.get() }
+        me
     }
-    pub fn unlock(&self, me: usize) {
-        self.levels[me].store(self.levels.len(), Ordering::Release)
+    unsafe fn release(&self, me: usize) {
+        self.levels[me].store(self.levels.len(), Ordering::SeqCst)
+    }
+    fn value(&self) -> &UnsafeCell<T> {
+        &self.value
+    }
+    fn ids(&self) -> Option<&ThreadIds> {
+        Some(&self.ids)
     }
 }
 unsafe impl<T> Send for FilterLock<T> {}
@@ -218,32 +479,14 @@ unsafe impl<T> Sync for FilterLock<T> {}
 
 #[test]
 fn test_filter_lock() {
-    let cpus = num_cpus::get();
-    println!("{}", cpus);
-    let lo = Arc::new(FilterLock::new(0, 30));
-    let mut jhs = vec![];
-    for _ in 0..30 {
-        let l = lo.clone();
-        let tid = spawn(move |tid| {
-            for _ in 0..300 {
-                let num = l.lock(tid);
-                *num = *num + 1;
-                l.unlock(tid)
-            }
-        });
-        jhs.push(tid)
-    }
-
-    for jh in jhs {
-        jh.join().unwrap()
-    }
-    println!("value is {}", lo.lock(1))
+    counter_test(FilterLock::new(0, 30), 30, 300)
 }
 
-struct Bakery<T> {
+pub struct Bakery<T> {
     value: UnsafeCell<T>,
     labels: Vec<AtomicUsize>,
     flags: Vec<AtomicBool>,
+    ids: ThreadIds,
 }
 impl<T> Bakery<T> {
     pub fn new(a: T, n: usize) -> Bakery<T> {
@@ -256,16 +499,19 @@ impl<T> Bakery<T> {
         }
         Bakery {
             value: UnsafeCell::new(a),
-            labels: labels,
-            flags: flags,
+            labels,
+            flags,
+            ids: ThreadIds::new(n),
         }
     }
-
-    pub fn lock(&self, me: usize) -> &mut T {
-        self.flags[me].store(true, Ordering::Relaxed);
+}
+unsafe impl<T> Lock<T> for Bakery<T> {
+    type Token = usize;
+    unsafe fn acquire(&self, me: usize) -> usize {
+        self.flags[me].store(true, Ordering::SeqCst);
 
         let m = self.labels.iter().fold(0, |a, x| {
-            let xx = x.load(Ordering::Relaxed);
+            let xx = x.load(Ordering::SeqCst);
             if a < xx {
                 xx
             } else {
@@ -273,46 +519,128 @@ impl<T> Bakery<T> {
             }
         }) + 1;
 
-        self.labels[me].store(m, Ordering::Relaxed);
+        self.labels[me].store(m, Ordering::SeqCst);
 
         while (0..self.flags.len()).any(|t| {
             t != me
-                && self.flags[t].load(Ordering::Relaxed)
-                && (self.labels[t].load(Ordering::Relaxed), t) < (m, me)
+                && self.flags[t].load(Ordering::SeqCst)
+                && (self.labels[t].load(Ordering::SeqCst), t) < (m, me)
         }) {
             thread::yield_now()
         }
-
-        unsafe { &mut *self.value.get() }
+        me
     }
 
-    fn unlock(&self, me: usize) {
+    unsafe fn release(&self, me: usize) {
         self.flags[me].store(false, Ordering::SeqCst);
     }
+    fn value(&self) -> &UnsafeCell<T> {
+        &self.value
+    }
+    fn ids(&self) -> Option<&ThreadIds> {
+        Some(&self.ids)
+    }
 }
 unsafe impl<T> Send for Bakery<T> {}
 unsafe impl<T> Sync for Bakery<T> {}
 
 #[test]
 fn test_bakery() {
-    let cpus = num_cpus::get();
-    println!("{}", cpus);
-    let lo = Arc::new(Bakery::new(0, 100));
-    let mut jhs = vec![];
-    for _ in 0..100 {
-        let l = lo.clone();
-        let a = spawn(move |tid| {
-            for _ in 0..600 {
-                let num = l.lock(tid);
-                *num = *num + 1;
-                l.unlock(tid)
+    counter_test(Bakery::new(0, 100), 100, 600)
+}
+
+// binary tree of two thread Peterson locks, a thread starts at its leaf and has to win
+// every node on the way up to the root. tree[0] is the root, the children of i are 2i+1 and 2i+2
+pub struct TournamentLock<T> {
+    value: UnsafeCell<T>,
+    tree: Vec<Peterson<()>>,
+    ids: ThreadIds,
+}
+impl<T> TournamentLock<T> {
+    pub fn new(a: T, n: usize) -> TournamentLock<T> {
+        let leaves = n.next_power_of_two();
+        TournamentLock {
+            value: UnsafeCell::new(a),
+            tree: (1..leaves).map(|_| Peterson::new(())).collect(),
+            ids: ThreadIds::new(n),
+        }
+    }
+    // (node, side) pairs from the leaf to the root
+    fn path(&self, tid: usize) -> impl Iterator<Item = (usize, usize)> {
+        let mut pos = self.tree.len() + tid;
+        std::iter::from_fn(move || {
+            if pos == 0 {
+                return None;
             }
-        });
-        jhs.push(a)
+            let step = ((pos - 1) / 2, (pos - 1) % 2);
+            pos = step.0;
+            Some(step)
+        })
+    }
+}
+unsafe impl<T> Lock<T> for TournamentLock<T> {
+    type Token = usize;
+    unsafe fn acquire(&self, tid: usize) -> usize {
+        let leaves = self.tree.len() + 1;
+        assert!(tid < leaves, "thread id {} out of range", tid);
+        for (node, side) in self.path(tid) {
+            self.tree[node].acquire(side);
+        }
+        tid
+    }
+    // root first, a thread still holding a lower node keeps its rivals out of the upper ones
+    unsafe fn release(&self, tid: usize) {
+        let path: Vec<_> = self.path(tid).collect();
+        for &(node, side) in path.iter().rev() {
+            self.tree[node].release(side);
+        }
+    }
+    fn value(&self) -> &UnsafeCell<T> {
+        &self.value
     }
+    fn ids(&self) -> Option<&ThreadIds> {
+        Some(&self.ids)
+    }
+}
+unsafe impl<T> Send for TournamentLock<T> {}
+unsafe impl<T> Sync for TournamentLock<T> {}
 
-    for jh in jhs {
-        jh.join().unwrap()
+#[test]
+fn test_tournament() {
+    counter_test(TournamentLock::new(0, 5), 5, 300)
+}
+
+#[test]
+fn test_tournament_fairness() {
+    let threads = 8;
+    let results = vec![
+        (
+            "tournament",
+            fairness_test(
+                TournamentLock::new(vec![0; threads], threads),
+                threads,
+                20000,
+            ),
+        ),
+        (
+            "filter",
+            fairness_test(FilterLock::new(vec![0; threads], threads), threads, 20000),
+        ),
+        (
+            "bakery",
+            fairness_test(Bakery::new(vec![0; threads], threads), threads, 20000),
+        ),
+    ];
+    for (name, counts) in results {
+        let counts_f: Vec<f64> = counts.iter().map(|&c| c as f64).collect();
+        let jain = super::bench::jain(&counts_f);
+        println!("{} {:?} jain {:.3}", name, counts, jain);
+        assert_eq!(counts.iter().sum::<usize>(), 20000);
+        // 1 is perfectly fair, 1/threads is one thread taking everything.
+        // filter and bakery are only printed for comparison, on several cores how fair they come
+        // out depends on the scheduler
+        if name == "tournament" {
+            assert!(jain > 0.8, "{} is unfair: {:?}", name, counts);
+        }
     }
-    println!("value is {}", lo.lock(0))
 }
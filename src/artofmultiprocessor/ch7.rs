@@ -1,59 +1,53 @@
-use chrono::prelude::*;
+use super::ch2::{counter_test, AnyTidLock, Lock, LockGuard};
 use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
 use std::{
-    cell::{Cell, RefCell, UnsafeCell},
-    sync::Arc,
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
     thread,
+    time::{Duration, Instant},
 };
-use thread::JoinHandle;
 // test and test and set lock with exponential backoff
 pub struct TTASLock<T>(UnsafeCell<T>, AtomicBool);
 impl<T> TTASLock<T> {
     pub fn new(a: T) -> TTASLock<T> {
         TTASLock(UnsafeCell::new(a), AtomicBool::new(false))
     }
-    pub fn lock(&self) -> &mut T {
+}
+unsafe impl<T> Lock<T> for TTASLock<T> {
+    type Token = ();
+    unsafe fn acquire(&self, _: usize) {
         let mut bo = Backoff::new(500, 10000);
         loop {
-            while self.1.load(Ordering::Relaxed) {}
-            if self.1.compare_and_swap(false, true, Ordering::SeqCst) {
+            while self.1.load(Ordering::Relaxed) {
+                std::hint::spin_loop()
+            }
+            if self
+                .1
+                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+            {
                 let t = bo.next();
                 thread::sleep(std::time::Duration::from_micros(t));
             } else {
                 break;
             }
         }
-
-        unsafe { &mut *self.0.get() }
     }
-    pub fn unlock(&self) {
-        self.1.store(false, Ordering::Relaxed)
+    unsafe fn release(&self, _: ()) {
+        self.1.store(false, Ordering::Release)
+    }
+    fn value(&self) -> &UnsafeCell<T> {
+        &self.0
     }
 }
+unsafe impl<T> AnyTidLock<T> for TTASLock<T> {}
 unsafe impl<T> Send for TTASLock<T> {}
 unsafe impl<T> Sync for TTASLock<T> {}
 
 pub fn tas_test() {
-    let lo = Arc::new(TTASLock::new(0));
-    let mut jhs = vec![];
     let now = std::time::SystemTime::now();
-    for _ in 0..50 {
-        let l = lo.clone();
-        let tid = thread::spawn(move || {
-            for _ in 0..3000 {
-                let num = l.lock();
-                *num = *num + 1;
-                l.unlock()
-            }
-        });
-        jhs.push(tid)
-    }
-
-    for jh in jhs {
-        jh.join().unwrap()
-    }
-
-    println!("value is {} {:?}", lo.lock(), now.elapsed())
+    counter_test(TTASLock::new(0), 50, 3000);
+    println!("{:?}", now.elapsed())
 }
 #[test]
 pub fn tt() {
@@ -66,10 +60,10 @@ pub struct Backoff {
 }
 
 impl Backoff {
-    fn new(min: u64, max: u64) -> Backoff {
+    pub(crate) fn new(min: u64, max: u64) -> Backoff {
         Backoff { max, current: min }
     }
-    fn next(&mut self) -> u64 {
+    pub(crate) fn next(&mut self) -> u64 {
         let mut rng = rand::thread_rng();
         let n1: u64 = rng.gen::<u64>() % self.current;
         self.current = std::cmp::min(self.max, self.current * 2);
@@ -77,151 +71,1044 @@ impl Backoff {
     }
 }
 
+// hierarchical backoff lock, threads of the owner's cluster back off shorter than the others
+// so the lock tends to stay within a cluster. the cluster of a thread is tid % clusters
+const FREE: usize = usize::MAX;
+pub struct HBOLock<T> {
+    value: UnsafeCell<T>,
+    state: AtomicUsize,
+    clusters: usize,
+}
+impl<T> HBOLock<T> {
+    pub fn new(a: T, clusters: usize) -> HBOLock<T> {
+        assert!(clusters > 0, "need at least one cluster");
+        HBOLock {
+            value: UnsafeCell::new(a),
+            state: AtomicUsize::new(FREE),
+            clusters,
+        }
+    }
+}
+unsafe impl<T> Lock<T> for HBOLock<T> {
+    type Token = ();
+    unsafe fn acquire(&self, tid: usize) {
+        let cluster = tid % self.clusters;
+        let mut local = Backoff::new(10, 500);
+        let mut remote = Backoff::new(500, 10000);
+        loop {
+            match self
+                .state
+                .compare_exchange(FREE, cluster, Ordering::Acquire, Ordering::Relaxed)
+            {
+                Ok(_) => return,
+                Err(owner) if owner == cluster => {
+                    thread::sleep(Duration::from_micros(local.next()))
+                }
+                Err(_) => thread::sleep(Duration::from_micros(remote.next())),
+            }
+        }
+    }
+    unsafe fn release(&self, _: ()) {
+        self.state.store(FREE, Ordering::Release)
+    }
+    fn value(&self) -> &UnsafeCell<T> {
+        &self.value
+    }
+}
+// tid only picks the cluster
+unsafe impl<T> AnyTidLock<T> for HBOLock<T> {}
+unsafe impl<T> Send for HBOLock<T> {}
+unsafe impl<T> Sync for HBOLock<T> {}
+
+#[test]
+pub fn hbo() {
+    counter_test(HBOLock::new(0, 2), 8, 500)
+}
+
+// while a thread holds the lock the state names its cluster
+#[test]
+pub fn hbo_cluster() {
+    use super::ch2::{spawn, ThreadIds};
+    use std::sync::Arc;
+    let lo = Arc::new(HBOLock::new(0, 2));
+    let ids = Arc::new(ThreadIds::new(4));
+    let jhs: Vec<_> = (0..4)
+        .map(|_| {
+            spawn(&lo, &ids, move |l, id| {
+                let tid = id.get();
+                for _ in 0..100 {
+                    let mut num = l.lock(id);
+                    assert_eq!(l.state.load(Ordering::Relaxed), tid % 2);
+                    *num += 1;
+                }
+            })
+        })
+        .collect();
+    for jh in jhs {
+        jh.join().unwrap()
+    }
+    assert_eq!(*lo.lock_any(), 400);
+    assert_eq!(lo.state.load(Ordering::Relaxed), FREE);
+}
+
+// cohort lock, a global TTASLock plus one local TTASLock per cluster.
+// the owner hands the global lock to a waiter of its own cluster by only releasing the local lock,
+// at most max_passes times in a row so the other clusters get their turn
+struct Cohort {
+    lock: TTASLock<()>,
+    waiting: AtomicUsize,
+    // only touched while holding lock
+    has_global: AtomicBool,
+    passes: AtomicUsize,
+}
+pub struct CohortLock<T> {
+    value: UnsafeCell<T>,
+    global: TTASLock<()>,
+    cohorts: Vec<Cohort>,
+    max_passes: usize,
+}
+impl<T> CohortLock<T> {
+    pub fn new(a: T, clusters: usize, max_passes: usize) -> CohortLock<T> {
+        assert!(clusters > 0, "need at least one cluster");
+        CohortLock {
+            value: UnsafeCell::new(a),
+            global: TTASLock::new(()),
+            cohorts: (0..clusters)
+                .map(|_| Cohort {
+                    lock: TTASLock::new(()),
+                    waiting: AtomicUsize::new(0),
+                    has_global: AtomicBool::new(false),
+                    passes: AtomicUsize::new(0),
+                })
+                .collect(),
+            max_passes,
+        }
+    }
+}
+unsafe impl<T> Lock<T> for CohortLock<T> {
+    type Token = usize;
+    unsafe fn acquire(&self, tid: usize) -> usize {
+        let cluster = tid % self.cohorts.len();
+        let cohort = &self.cohorts[cluster];
+        cohort.waiting.fetch_add(1, Ordering::SeqCst);
+        cohort.lock.acquire(tid);
+        cohort.waiting.fetch_sub(1, Ordering::SeqCst);
+        if !cohort.has_global.load(Ordering::Relaxed) {
+            self.global.acquire(tid);
+            cohort.has_global.store(true, Ordering::Relaxed);
+            cohort.passes.store(0, Ordering::Relaxed);
+        }
+        cluster
+    }
+    unsafe fn release(&self, cluster: usize) {
+        let cohort = &self.cohorts[cluster];
+        let passes = cohort.passes.load(Ordering::Relaxed);
+        if cohort.waiting.load(Ordering::SeqCst) > 0 && passes < self.max_passes {
+            cohort.passes.store(passes + 1, Ordering::Relaxed);
+        } else {
+            cohort.has_global.store(false, Ordering::Relaxed);
+            self.global.release(());
+        }
+        cohort.lock.release(())
+    }
+    fn value(&self) -> &UnsafeCell<T> {
+        &self.value
+    }
+}
+// tid only picks the cluster
+unsafe impl<T> AnyTidLock<T> for CohortLock<T> {}
+unsafe impl<T> Send for CohortLock<T> {}
+unsafe impl<T> Sync for CohortLock<T> {}
+
+#[test]
+pub fn cohort() {
+    counter_test(CohortLock::new(0, 2, 10), 8, 500)
+}
+
+// records the cluster and pass count of every acquisition. a passed lock always comes from
+// the previous holder of the same cluster and a cluster keeps it at most max_passes times
+#[test]
+pub fn cohort_passes() {
+    use super::ch2::{spawn, ThreadIds};
+    use std::sync::Arc;
+    let max_passes = 3;
+    let lo = Arc::new(CohortLock::new(vec![], 2, max_passes));
+    let ids = Arc::new(ThreadIds::new(8));
+    let jhs: Vec<_> = (0..8)
+        .map(|_| {
+            spawn(&lo, &ids, move |l, id| {
+                let cluster = id.get() % 2;
+                for _ in 0..100 {
+                    let mut order = l.lock(id);
+                    order.push((cluster, l.cohorts[cluster].passes.load(Ordering::Relaxed)));
+                    // let the others queue up behind us
+                    thread::yield_now();
+                }
+            })
+        })
+        .collect();
+    for jh in jhs {
+        jh.join().unwrap()
+    }
+    let order = lo.lock_any();
+    assert_eq!(order.len(), 800);
+    assert_eq!(order[0].1, 0);
+    for w in order.windows(2) {
+        let ((prev_cluster, prev_passes), (cluster, passes)) = (w[0], w[1]);
+        assert!(passes <= max_passes);
+        if passes > 0 {
+            assert_eq!((cluster, passes), (prev_cluster, prev_passes + 1));
+        }
+    }
+    assert!(order.iter().any(|&(_, passes)| passes > 0));
+}
+
+// keeps T on its own cache line so neighbouring slots don't invalidate each other
+#[repr(align(64))]
+pub struct CachePadded<T>(pub T);
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+// anderson's array lock, every waiter spins on its own slot.
+// with more threads than slots two of them would share a slot, so waiters counts the
+// threads between acquire and release. acquire waits for a free slot, try_lock refuses
 pub struct ALock<T> {
     value: UnsafeCell<T>,
     ticket: AtomicUsize,
-    flags: [Cell<usize>; 100], // in order to prevent false sharing of cache lines
-}
-pub struct ALockGuard<'a, T> {
-    position: usize,
-    pub value: &'a mut T,
-    flags: &'a [Cell<usize>],
+    waiters: AtomicUsize,
+    flags: Vec<CachePadded<AtomicBool>>,
 }
 
 impl<T> ALock<T> {
     fn next(&self) -> usize {
         let old = self.ticket.fetch_add(1, Ordering::AcqRel);
-        old % 100
+        old % self.flags.len()
     }
     pub fn new(a: T, size: usize) -> ALock<T> {
-        let mut arr: [Cell<usize>; 100] = unsafe { std::mem::MaybeUninit::uninit().assume_init() };
-        for i in 0..100 {
-            arr[i] = Cell::new(0)
-        }
-        let alock = ALock {
+        assert!(size > 0, "ALock needs at least one slot");
+        ALock {
             value: UnsafeCell::new(a),
             ticket: AtomicUsize::new(0),
-            flags: arr,
-        };
-        alock.flags[0].set(1);
-        alock
+            waiters: AtomicUsize::new(0),
+            flags: (0..size)
+                .map(|i| CachePadded(AtomicBool::new(i == 0)))
+                .collect(),
+        }
     }
-    pub fn lock<'a>(&'a self) -> ALockGuard<'a, T> {
+    pub fn capacity(&self) -> usize {
+        self.flags.len()
+    }
+    // counts us in as a waiter unless every slot is taken
+    fn claim(&self) -> bool {
+        let len = self.flags.len();
+        self.waiters
+            .fetch_update(Ordering::AcqRel, Ordering::Relaxed, |w| {
+                if w < len {
+                    Some(w + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+    fn wait_turn(&self) -> usize {
         let num = self.next();
-        while self.flags[num].get() == 0 {
+        while !self.flags[num].load(Ordering::Acquire) {
             thread::yield_now();
         }
-
-        ALockGuard {
-            position: num,
-            value: unsafe { &mut *self.value.get() },
-            flags: &self.flags,
+        num
+    }
+    // Err(capacity) if capacity threads are already waiting or holding the lock,
+    // otherwise takes a slot and waits for its turn
+    pub fn try_lock(&self) -> Result<LockGuard<'_, T, ALock<T>>, usize> {
+        if !self.claim() {
+            return Err(self.flags.len());
+        }
+        let num = self.wait_turn();
+        Ok(unsafe { LockGuard::new(self, 0, num) })
+    }
+}
+unsafe impl<T> Lock<T> for ALock<T> {
+    type Token = usize;
+    unsafe fn acquire(&self, _: usize) -> usize {
+        while !self.claim() {
+            thread::yield_now();
         }
+        self.wait_turn()
+    }
+    // our slot is free again before the successor runs, so a thread arriving meanwhile fits
+    unsafe fn release(&self, position: usize) {
+        self.flags[position].store(false, Ordering::Relaxed);
+        self.waiters.fetch_sub(1, Ordering::AcqRel);
+        self.flags[(position + 1) % self.flags.len()].store(true, Ordering::Release);
+    }
+    fn value(&self) -> &UnsafeCell<T> {
+        &self.value
     }
 }
+unsafe impl<T> AnyTidLock<T> for ALock<T> {}
 
 unsafe impl<T> Send for ALock<T> {}
 unsafe impl<T> Sync for ALock<T> {}
 
-impl<'a, T> Drop for ALockGuard<'a, T> {
-    fn drop(&mut self) {
-        self.flags[self.position].set(0);
-        self.flags[(self.position + 1) % 100].set(1);
+pub fn array_test() {
+    let now = std::time::SystemTime::now();
+    counter_test(ALock::new(0, 100), 50, 3000);
+    println!("{:?}", now.elapsed())
+}
+#[test]
+pub fn at() {
+    array_test()
+}
+#[test]
+pub fn at_exact_capacity() {
+    counter_test(ALock::new(0, 8), 8, 1000)
+}
+#[test]
+pub fn at_over_capacity() {
+    let lo = ALock::new(0, 1);
+    let held = lo.lock_any();
+    assert_eq!(lo.try_lock().err(), Some(1));
+    drop(held);
+    assert!(lo.try_lock().is_ok());
+    // the others wait for a slot
+    counter_test(ALock::new(0, 2), 8, 500)
+}
+
+// composite lock, a small array of queue nodes taken with backoff and then queued like clh.
+// a waiter that times out marks its node aborted and its successor skips over it.
+// tail packs the node index + 1 (0 = empty) in the low 32 bits and a stamp against aba above it,
+// the top bit is used by the fast path variant
+const COMPOSITE_FREE: usize = 0;
+const COMPOSITE_WAITING: usize = 1;
+const COMPOSITE_RELEASED: usize = 2;
+const COMPOSITE_ABORTED: usize = 3;
+const NODE_MASK: usize = 0xffff_ffff;
+const FASTPATH: usize = 1 << (usize::BITS - 1);
+
+struct CompositeNode {
+    state: AtomicUsize,
+    // index + 1 of the predecessor, set when aborting
+    pred: AtomicUsize,
+}
+pub struct CompositeLock<T> {
+    value: UnsafeCell<T>,
+    tail: AtomicUsize,
+    waiting: Vec<CompositeNode>,
+}
+
+fn stamped(node: usize, tail: usize) -> usize {
+    let stamp = (tail & !NODE_MASK & !FASTPATH).wrapping_add(NODE_MASK + 1) & !FASTPATH;
+    stamp | (tail & FASTPATH) | node
+}
+
+impl<T> CompositeLock<T> {
+    pub fn new(a: T, size: usize) -> CompositeLock<T> {
+        assert!(size > 0, "CompositeLock needs at least one node");
+        CompositeLock {
+            value: UnsafeCell::new(a),
+            tail: AtomicUsize::new(0),
+            waiting: (0..size)
+                .map(|_| CompositeNode {
+                    state: AtomicUsize::new(COMPOSITE_FREE),
+                    pred: AtomicUsize::new(0),
+                })
+                .collect(),
+        }
+    }
+    pub fn try_lock_for(&self, timeout: Duration) -> Option<LockGuard<'_, T, CompositeLock<T>>> {
+        self.try_acquire(Some(Instant::now() + timeout))
+            .map(|node| unsafe { LockGuard::new(self, 0, node) })
+    }
+    // returns the index of the node that has to be released
+    fn try_acquire(&self, deadline: Option<Instant>) -> Option<usize> {
+        let timed_out = || deadline.is_some_and(|d| Instant::now() >= d);
+        let node = self.acquire_node(timed_out)?;
+        let pred = self.splice_node(node, timed_out)?;
+        self.wait_for_predecessor(pred, node, timed_out)?;
+        Some(node)
+    }
+    fn acquire_node(&self, timed_out: impl Fn() -> bool) -> Option<usize> {
+        let node = rand::thread_rng().gen_range(0, self.waiting.len());
+        let qnode = &self.waiting[node];
+        let mut bo = Backoff::new(10, 1000);
+        loop {
+            if qnode
+                .state
+                .compare_exchange(
+                    COMPOSITE_FREE,
+                    COMPOSITE_WAITING,
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                return Some(node);
+            }
+            // the node is the tail and nobody is queued behind it, we can take it out of the queue
+            let tail = self.tail.load(Ordering::Acquire);
+            let state = qnode.state.load(Ordering::Acquire);
+            if (state == COMPOSITE_ABORTED || state == COMPOSITE_RELEASED)
+                && tail & NODE_MASK == node + 1
+            {
+                let pred = if state == COMPOSITE_ABORTED {
+                    qnode.pred.load(Ordering::Acquire)
+                } else {
+                    0
+                };
+                if self
+                    .tail
+                    .compare_exchange(
+                        tail,
+                        stamped(pred, tail),
+                        Ordering::AcqRel,
+                        Ordering::Relaxed,
+                    )
+                    .is_ok()
+                {
+                    qnode.state.store(COMPOSITE_WAITING, Ordering::Release);
+                    return Some(node);
+                }
+            }
+            thread::sleep(Duration::from_micros(bo.next()));
+            if timed_out() {
+                return None;
+            }
+        }
+    }
+    // returns the predecessor index + 1, 0 if the queue was empty
+    fn splice_node(&self, node: usize, timed_out: impl Fn() -> bool) -> Option<usize> {
+        loop {
+            let tail = self.tail.load(Ordering::Acquire);
+            if timed_out() {
+                self.waiting[node]
+                    .state
+                    .store(COMPOSITE_FREE, Ordering::Release);
+                return None;
+            }
+            if self
+                .tail
+                .compare_exchange(
+                    tail,
+                    stamped(node + 1, tail),
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                return Some(tail & NODE_MASK);
+            }
+        }
+    }
+    fn wait_for_predecessor(
+        &self,
+        mut pred: usize,
+        node: usize,
+        timed_out: impl Fn() -> bool,
+    ) -> Option<()> {
+        if pred == 0 {
+            return Some(());
+        }
+        loop {
+            let state = self.waiting[pred - 1].state.load(Ordering::Acquire);
+            if state == COMPOSITE_RELEASED {
+                self.waiting[pred - 1]
+                    .state
+                    .store(COMPOSITE_FREE, Ordering::Release);
+                return Some(());
+            }
+            if state == COMPOSITE_ABORTED {
+                let aborted = pred - 1;
+                pred = self.waiting[aborted].pred.load(Ordering::Acquire);
+                self.waiting[aborted]
+                    .state
+                    .store(COMPOSITE_FREE, Ordering::Release);
+                if pred == 0 {
+                    return Some(());
+                }
+                continue;
+            }
+            if timed_out() {
+                self.waiting[node].pred.store(pred, Ordering::Release);
+                self.waiting[node]
+                    .state
+                    .store(COMPOSITE_ABORTED, Ordering::Release);
+                return None;
+            }
+            thread::yield_now()
+        }
     }
 }
+unsafe impl<T> Lock<T> for CompositeLock<T> {
+    type Token = usize;
+    unsafe fn acquire(&self, _: usize) -> usize {
+        self.try_acquire(None).unwrap()
+    }
+    unsafe fn release(&self, node: usize) {
+        self.waiting[node]
+            .state
+            .store(COMPOSITE_RELEASED, Ordering::Release)
+    }
+    fn value(&self) -> &UnsafeCell<T> {
+        &self.value
+    }
+}
+unsafe impl<T> AnyTidLock<T> for CompositeLock<T> {}
+unsafe impl<T> Send for CompositeLock<T> {}
+unsafe impl<T> Sync for CompositeLock<T> {}
 
-pub fn array_test() {
-    let lo = Arc::new(ALock::new(0, 100));
-    let mut jhs = vec![];
+// an uncontended thread only flips the FASTPATH bit of an empty tail,
+// queued threads wait for the bit to clear before they enter
+pub struct CompositeFastPathLock<T>(CompositeLock<T>);
+impl<T> CompositeFastPathLock<T> {
+    pub fn new(a: T, size: usize) -> CompositeFastPathLock<T> {
+        CompositeFastPathLock(CompositeLock::new(a, size))
+    }
+    pub fn try_lock_for(
+        &self,
+        timeout: Duration,
+    ) -> Option<LockGuard<'_, T, CompositeFastPathLock<T>>> {
+        self.try_acquire(Some(Instant::now() + timeout))
+            .map(|node| unsafe { LockGuard::new(self, 0, node) })
+    }
+    // None is the fast path
+    fn try_acquire(&self, deadline: Option<Instant>) -> Option<Option<usize>> {
+        let tail = self.0.tail.load(Ordering::Acquire);
+        if tail & (NODE_MASK | FASTPATH) == 0
+            && self
+                .0
+                .tail
+                .compare_exchange(
+                    tail,
+                    stamped(0, tail) | FASTPATH,
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+        {
+            return Some(None);
+        }
+        let node = self.0.try_acquire(deadline)?;
+        while self.0.tail.load(Ordering::Acquire) & FASTPATH != 0 {
+            if deadline.is_some_and(|d| Instant::now() >= d) {
+                // we are at the head of the queue, the successor skips our node
+                // and waits for the fast path holder itself
+                self.0.waiting[node].pred.store(0, Ordering::Release);
+                self.0.waiting[node]
+                    .state
+                    .store(COMPOSITE_ABORTED, Ordering::Release);
+                return None;
+            }
+            thread::yield_now()
+        }
+        Some(Some(node))
+    }
+}
+unsafe impl<T> Lock<T> for CompositeFastPathLock<T> {
+    type Token = Option<usize>;
+    unsafe fn acquire(&self, _: usize) -> Option<usize> {
+        self.try_acquire(None).unwrap()
+    }
+    unsafe fn release(&self, node: Option<usize>) {
+        match node {
+            Some(node) => self.0.release(node),
+            None => {
+                self.0.tail.fetch_and(!FASTPATH, Ordering::Release);
+            }
+        }
+    }
+    fn value(&self) -> &UnsafeCell<T> {
+        &self.0.value
+    }
+}
+unsafe impl<T> AnyTidLock<T> for CompositeFastPathLock<T> {}
+
+pub fn composite_test() {
+    let now = std::time::SystemTime::now();
+    counter_test(CompositeLock::new(0, 4), 50, 3000);
+    println!("composite {:?}", now.elapsed());
     let now = std::time::SystemTime::now();
-    for _ in 0..50 {
+    counter_test(CompositeFastPathLock::new(0, 4), 50, 3000);
+    println!("composite fast path {:?}", now.elapsed())
+}
+#[test]
+pub fn composite() {
+    composite_test()
+}
+
+// like to_contended, waiters give up and the count still adds up
+#[test]
+pub fn composite_timeout() {
+    let lo = std::sync::Arc::new(CompositeLock::new(0, 4));
+    let mut jhs = vec![];
+    for _ in 0..8 {
         let l = lo.clone();
-        let tid = thread::spawn(move || {
-            for _ in 0..3000 {
-                let num = l.lock();
-                *num.value = *num.value + 1;
+        jhs.push(thread::spawn(move || {
+            let mut acquired = 0;
+            for _ in 0..500 {
+                if let Some(mut num) = l.try_lock_for(Duration::from_micros(50)) {
+                    *num += 1;
+                    acquired += 1;
+                }
             }
-        });
-        jhs.push(tid)
+            acquired
+        }))
+    }
+    let acquired: usize = jhs.into_iter().map(|jh| jh.join().unwrap()).sum();
+    assert_eq!(*lo.lock_any(), acquired);
+}
+
+// a queued waiter gives up while the fast path holder keeps the lock
+#[test]
+pub fn composite_fast_path_timeout() {
+    let lo = std::sync::Arc::new(CompositeFastPathLock::new(0, 4));
+    let mut num = lo.lock_any();
+    let l = lo.clone();
+    let jh = thread::spawn(move || l.try_lock_for(Duration::from_millis(10)).is_none());
+    assert!(jh.join().unwrap());
+    *num += 1;
+    drop(num);
+    *lo.try_lock_for(Duration::from_secs(10)).unwrap() += 1;
+    assert_eq!(*lo.lock_any(), 2);
+}
+
+#[test]
+#[should_panic(expected = "CompositeLock needs at least one node")]
+pub fn composite_no_nodes() {
+    CompositeFastPathLock::new(0, 0);
+}
+
+// clh queue lock, every waiter spins on the node of its predecessor.
+// on unlock a thread gives its own node to the successor and takes over the predecessor's node,
+// so a CLHNode points to a different allocation after every use
+pub struct CLHNode(*mut AtomicBool);
+impl CLHNode {
+    pub fn new() -> CLHNode {
+        CLHNode(Box::into_raw(Box::new(AtomicBool::new(false))))
+    }
+}
+impl Default for CLHNode {
+    fn default() -> CLHNode {
+        CLHNode::new()
+    }
+}
+// a node is only dropped while nobody else can see it
+impl Drop for CLHNode {
+    fn drop(&mut self) {
+        unsafe { drop(Box::from_raw(self.0)) }
     }
+}
+unsafe impl Send for CLHNode {}
+
+pub struct CLHLock<T> {
+    value: UnsafeCell<T>,
+    tail: AtomicPtr<AtomicBool>,
+}
+pub struct CLHGuard<'a, T> {
+    lock: &'a CLHLock<T>,
+    node: &'a mut CLHNode,
+    pred: *mut AtomicBool,
+}
 
+impl<T> CLHLock<T> {
+    pub fn new(a: T) -> CLHLock<T> {
+        CLHLock {
+            value: UnsafeCell::new(a),
+            tail: AtomicPtr::new(Box::into_raw(Box::new(AtomicBool::new(false)))),
+        }
+    }
+    // the guard must be dropped, until then the node's allocation is the tail or spun on by the
+    // successor. if it is leaked (mem::forget) the node must never be used or dropped again
+    pub unsafe fn lock_with<'a>(&'a self, node: &'a mut CLHNode) -> CLHGuard<'a, T> {
+        let pred = self.enqueue(node);
+        CLHGuard {
+            lock: self,
+            node,
+            pred,
+        }
+    }
+    // returns the predecessor, which is ours once we unlock
+    fn enqueue(&self, node: &CLHNode) -> *mut AtomicBool {
+        unsafe { (*node.0).store(true, Ordering::Relaxed) };
+        let pred = self.tail.swap(node.0, Ordering::AcqRel);
+        while unsafe { (*pred).load(Ordering::Acquire) } {
+            thread::yield_now()
+        }
+        pred
+    }
+    fn dequeue(node: &mut CLHNode, pred: *mut AtomicBool) {
+        unsafe { (*node.0).store(false, Ordering::Release) };
+        node.0 = pred;
+    }
+}
+unsafe impl<T> Lock<T> for CLHLock<T> {
+    type Token = (CLHNode, *mut AtomicBool);
+    unsafe fn acquire(&self, _: usize) -> (CLHNode, *mut AtomicBool) {
+        let node = CLHNode::new();
+        let pred = self.enqueue(&node);
+        (node, pred)
+    }
+    unsafe fn release(&self, (mut node, pred): (CLHNode, *mut AtomicBool)) {
+        Self::dequeue(&mut node, pred)
+    }
+    fn value(&self) -> &UnsafeCell<T> {
+        &self.value
+    }
+}
+unsafe impl<T> AnyTidLock<T> for CLHLock<T> {}
+// the tail node is owned by the lock, every other node by some CLHNode
+impl<T> Drop for CLHLock<T> {
+    fn drop(&mut self) {
+        drop(CLHNode(*self.tail.get_mut()))
+    }
+}
+
+unsafe impl<T> Send for CLHLock<T> {}
+unsafe impl<T> Sync for CLHLock<T> {}
+
+impl<'a, T> Deref for CLHGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+impl<'a, T> DerefMut for CLHGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+impl<'a, T> Drop for CLHGuard<'a, T> {
+    fn drop(&mut self) {
+        CLHLock::<T>::dequeue(self.node, self.pred)
+    }
+}
+
+pub fn clh_test() {
+    let now = std::time::SystemTime::now();
+    counter_test(CLHLock::new(0), 50, 3000);
+    println!("{:?}", now.elapsed())
+}
+#[test]
+pub fn clh() {
+    clh_test()
+}
+
+// one node per thread shared by two locks, recycled on every unlock
+#[test]
+pub fn clh_own_node() {
+    let locks = std::sync::Arc::new((CLHLock::new(0), CLHLock::new(0)));
+    let mut jhs = vec![];
+    for _ in 0..8 {
+        let l = locks.clone();
+        jhs.push(thread::spawn(move || {
+            let mut node = CLHNode::new();
+            for _ in 0..1000 {
+                // the guards are dropped right away
+                *unsafe { l.0.lock_with(&mut node) } += 1;
+                *unsafe { l.1.lock_with(&mut node) } += 2;
+            }
+        }))
+    }
     for jh in jhs {
         jh.join().unwrap()
     }
+    let mut node = CLHNode::new();
+    assert_eq!(*unsafe { locks.0.lock_with(&mut node) }, 8000);
+    assert_eq!(*unsafe { locks.1.lock_with(&mut node) }, 16000);
+}
 
-    println!("value is {} {:?}", lo.lock().value, now.elapsed())
+// abortable clh lock. a node points to its predecessor once its thread gave up
+// and to AVAILABLE once it unlocked, a waiter follows abandoned nodes until it finds AVAILABLE.
+// a node is freed by the only thread that still looks at it: its successor,
+// or its owner when the tail could be moved back past it
+pub struct TONode {
+    pred: AtomicPtr<TONode>,
 }
-#[test]
-pub fn at() {
-    array_test()
+static AVAILABLE: TONode = TONode {
+    pred: AtomicPtr::new(std::ptr::null_mut()),
+};
+fn available() -> *mut TONode {
+    &AVAILABLE as *const TONode as *mut TONode
 }
 
-thread_local! {
-      pub static mynode: AtomicBool = AtomicBool::new(false);
-      pub static mypred: Cell<&'static AtomicBool> = Cell::new(&falsenode);
+pub struct TOLock<T> {
+    value: UnsafeCell<T>,
+    tail: AtomicPtr<TONode>,
 }
 
-pub static falsenode: AtomicBool = AtomicBool::new(false);
-struct CLHLock<T>(UnsafeCell<T>, AtomicPtr<AtomicBool>);
+impl<T> TOLock<T> {
+    pub fn new(a: T) -> TOLock<T> {
+        TOLock {
+            value: UnsafeCell::new(a),
+            tail: AtomicPtr::new(std::ptr::null_mut()),
+        }
+    }
+    pub fn try_lock_for(&self, timeout: Duration) -> Option<LockGuard<'_, T, TOLock<T>>> {
+        self.try_acquire(Some(Instant::now() + timeout))
+            .map(|node| unsafe { LockGuard::new(self, 0, node) })
+    }
+    fn try_acquire(&self, deadline: Option<Instant>) -> Option<*mut TONode> {
+        let node = Box::into_raw(Box::new(TONode {
+            pred: AtomicPtr::new(std::ptr::null_mut()),
+        }));
+        let mut pred = self.tail.swap(node, Ordering::AcqRel);
+        if pred.is_null() {
+            return Some(node);
+        }
+        while deadline.is_none_or(|d| Instant::now() < d) {
+            let predpred = unsafe { (*pred).pred.load(Ordering::Acquire) };
+            if predpred == available() {
+                unsafe { drop(Box::from_raw(pred)) };
+                return Some(node);
+            } else if !predpred.is_null() {
+                // pred gave up, nobody else will look at it
+                unsafe { drop(Box::from_raw(pred)) };
+                pred = predpred;
+            }
+            thread::yield_now()
+        }
+        if self
+            .tail
+            .compare_exchange(node, pred, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+        {
+            unsafe { drop(Box::from_raw(node)) };
+        } else {
+            unsafe { (*node).pred.store(pred, Ordering::Release) };
+        }
+        None
+    }
+}
+unsafe impl<T> Lock<T> for TOLock<T> {
+    type Token = *mut TONode;
+    unsafe fn acquire(&self, _: usize) -> *mut TONode {
+        self.try_acquire(None).unwrap()
+    }
+    unsafe fn release(&self, node: *mut TONode) {
+        if self
+            .tail
+            .compare_exchange(
+                node,
+                std::ptr::null_mut(),
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+        {
+            drop(Box::from_raw(node));
+        } else {
+            (*node).pred.store(available(), Ordering::Release);
+        }
+    }
+    fn value(&self) -> &UnsafeCell<T> {
+        &self.value
+    }
+}
+unsafe impl<T> AnyTidLock<T> for TOLock<T> {}
+// a tail left behind by an abandoned waiter has already been unlocked
+impl<T> Drop for TOLock<T> {
+    fn drop(&mut self) {
+        let tail = *self.tail.get_mut();
+        if !tail.is_null() {
+            unsafe { drop(Box::from_raw(tail)) }
+        }
+    }
+}
 
-impl<T> CLHLock<T> {
-    fn new(a: T) -> CLHLock<T> {
-        CLHLock(
-            UnsafeCell::new(a),
-            AtomicPtr::new(&falsenode as *const AtomicBool as *mut AtomicBool),
-        )
-    }
-
-    fn lock(&self) -> &mut T {
-        mynode.with(|n| {
-            n.store(true, Ordering::SeqCst);
-            println!("boran");
-            let p = self
-                .1
-                .swap(n as *const AtomicBool as *mut AtomicBool, Ordering::SeqCst);
-            mypred.with(|m| {
-                m.set(unsafe { p.as_ref().unwrap() });
-                while m.get().load(Ordering::SeqCst) {
-                    thread::yield_now()
+unsafe impl<T> Send for TOLock<T> {}
+unsafe impl<T> Sync for TOLock<T> {}
+
+#[test]
+pub fn to() {
+    counter_test(TOLock::new(0), 50, 3000)
+}
+
+// waiters behind a long critical section give up, the queue keeps working after them
+#[test]
+pub fn to_timeout() {
+    let lo = std::sync::Arc::new(TOLock::new(0));
+    let held = lo.lock_any();
+    let mut jhs = vec![];
+    for _ in 0..4 {
+        let l = lo.clone();
+        jhs.push(thread::spawn(move || {
+            l.try_lock_for(Duration::from_millis(20)).is_none()
+        }))
+    }
+    for jh in jhs {
+        assert!(jh.join().unwrap())
+    }
+    drop(held);
+    assert!(lo.try_lock_for(Duration::from_millis(20)).is_some());
+}
+
+// every thread only counts the increments it got the lock for
+#[test]
+pub fn to_contended() {
+    let lo = std::sync::Arc::new(TOLock::new(0));
+    let mut jhs = vec![];
+    for _ in 0..8 {
+        let l = lo.clone();
+        jhs.push(thread::spawn(move || {
+            let mut acquired = 0;
+            for _ in 0..2000 {
+                if let Some(mut num) = l.try_lock_for(Duration::from_micros(50)) {
+                    *num += 1;
+                    acquired += 1;
                 }
-            });
-        });
-        println!("asd");
-        unsafe { &mut *self.0.get() }
+            }
+            acquired
+        }))
+    }
+    let acquired: usize = jhs.into_iter().map(|jh| jh.join().unwrap()).sum();
+    assert_eq!(*lo.lock_any(), acquired);
+}
+
+// mcs queue lock, every waiter spins on its own node which the predecessor clears on unlock.
+// the caller owns the node, it has to stay in place until the guard is dropped
+pub struct QNode {
+    locked: AtomicBool,
+    next: AtomicPtr<QNode>,
+}
+impl QNode {
+    pub fn new() -> QNode {
+        QNode {
+            locked: AtomicBool::new(false),
+            next: AtomicPtr::new(std::ptr::null_mut()),
+        }
+    }
+}
+impl Default for QNode {
+    fn default() -> QNode {
+        QNode::new()
     }
+}
 
-    fn unlock(&self) {
-        mynode.with(|n| {
-            println!("dropped");
+pub struct MCSLock<T> {
+    value: UnsafeCell<T>,
+    tail: AtomicPtr<QNode>,
+}
+pub struct MCSGuard<'a, T> {
+    lock: &'a MCSLock<T>,
+    node: &'a QNode,
+}
 
-            n.store(false, Ordering::SeqCst);
-        });
+impl<T> MCSLock<T> {
+    pub fn new(a: T) -> MCSLock<T> {
+        MCSLock {
+            value: UnsafeCell::new(a),
+            tail: AtomicPtr::new(std::ptr::null_mut()),
+        }
+    }
+    // the guard must be dropped, successors write to the node until it is. if it is leaked
+    // (mem::forget) the node must never be used, moved or freed again and the lock stays taken
+    pub unsafe fn lock_with<'a>(&'a self, node: &'a mut QNode) -> MCSGuard<'a, T> {
+        self.enqueue(node);
+        MCSGuard { lock: self, node }
+    }
+    fn enqueue(&self, node: &QNode) {
+        let me = node as *const QNode as *mut QNode;
+        node.locked.store(true, Ordering::Relaxed);
+        node.next.store(std::ptr::null_mut(), Ordering::Relaxed);
+        let pred = self.tail.swap(me, Ordering::AcqRel);
+        if !pred.is_null() {
+            unsafe { (*pred).next.store(me, Ordering::Release) };
+            while node.locked.load(Ordering::Acquire) {
+                thread::yield_now()
+            }
+        }
+    }
+    fn dequeue(&self, node: &QNode) {
+        let me = node as *const QNode as *mut QNode;
+        let mut next = node.next.load(Ordering::Acquire);
+        if next.is_null() {
+            if self
+                .tail
+                .compare_exchange(
+                    me,
+                    std::ptr::null_mut(),
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                return;
+            }
+            // a successor swapped itself in but has not linked to us yet
+            while next.is_null() {
+                thread::yield_now();
+                next = node.next.load(Ordering::Acquire);
+            }
+        }
+        unsafe { (*next).locked.store(false, Ordering::Release) }
+    }
+}
+unsafe impl<T> Lock<T> for MCSLock<T> {
+    type Token = Box<QNode>;
+    unsafe fn acquire(&self, _: usize) -> Box<QNode> {
+        let node = Box::new(QNode::new());
+        self.enqueue(&node);
+        node
+    }
+    unsafe fn release(&self, node: Box<QNode>) {
+        self.dequeue(&node)
+    }
+    fn value(&self) -> &UnsafeCell<T> {
+        &self.value
     }
 }
+unsafe impl<T> AnyTidLock<T> for MCSLock<T> {}
+unsafe impl<T> Send for MCSLock<T> {}
+unsafe impl<T> Sync for MCSLock<T> {}
 
-unsafe impl<T> Send for CLHLock<T> {}
-unsafe impl<T> Sync for CLHLock<T> {}
+impl<'a, T> Deref for MCSGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+impl<'a, T> DerefMut for MCSGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+impl<'a, T> Drop for MCSGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.dequeue(self.node)
+    }
+}
 
+pub fn mcs_test() {
+    let now = std::time::SystemTime::now();
+    counter_test(MCSLock::new(0), 50, 3000);
+    println!("{:?}", now.elapsed())
+}
 #[test]
-pub fn clh() {
-    let lo = Arc::new(CLHLock::new(0));
+pub fn mcs() {
+    mcs_test()
+}
+
+// one node per thread, reused for every acquisition
+#[test]
+pub fn mcs_own_node() {
+    let lo = std::sync::Arc::new(MCSLock::new(0));
     let mut jhs = vec![];
-    let now = std::time::SystemTime::now();
-    for _ in 0..1 {
+    for _ in 0..8 {
         let l = lo.clone();
-        let tid = thread::spawn(move || {
-            for _ in 0..3000 {
-                let num = l.lock();
-                *num = *num + 1;
-                l.unlock();
+        jhs.push(thread::spawn(move || {
+            let mut node = QNode::new();
+            for _ in 0..1000 {
+                // the guard is dropped right away
+                *unsafe { l.lock_with(&mut node) } += 1;
             }
-        });
-        jhs.push(tid)
+        }))
     }
-
     for jh in jhs {
         jh.join().unwrap()
     }
-
-    println!("value is {} {:?}", lo.lock(), now.elapsed())
+    assert_eq!(*unsafe { lo.lock_with(&mut QNode::new()) }, 8000);
 }
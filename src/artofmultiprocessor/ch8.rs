@@ -1,7 +1,726 @@
+use super::ch2::{AnyTidLock, Lock, LockGuard};
+use super::ch7::MCSLock;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::{
     cell::{Cell, UnsafeCell},
+    collections::VecDeque,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
     sync::Arc,
     thread,
+    time::{Duration, Instant},
 };
-use thread::JoinHandle;
+
+// readers-writers locks, same shape as ch2::Lock: a raw protocol plus guards releasing on drop
+pub trait ReadWriteLock<T> {
+    fn read_acquire(&self);
+    // must only be called by a thread that holds a read lock
+    unsafe fn read_release(&self);
+    fn write_acquire(&self);
+    // must only be called by the thread that holds the write lock
+    unsafe fn write_release(&self);
+    fn value(&self) -> &UnsafeCell<T>;
+
+    fn read(&self) -> ReadGuard<'_, T, Self>
+    where
+        Self: Sized,
+    {
+        self.read_acquire();
+        ReadGuard {
+            lock: self,
+            phantom: PhantomData,
+        }
+    }
+    fn write(&self) -> WriteGuard<'_, T, Self>
+    where
+        Self: Sized,
+    {
+        self.write_acquire();
+        WriteGuard {
+            lock: self,
+            phantom: PhantomData,
+        }
+    }
+}
+
+pub struct ReadGuard<'a, T, L: ReadWriteLock<T>> {
+    lock: &'a L,
+    phantom: PhantomData<&'a T>,
+}
+impl<'a, T, L: ReadWriteLock<T>> Deref for ReadGuard<'a, T, L> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value().get() }
+    }
+}
+impl<'a, T, L: ReadWriteLock<T>> Drop for ReadGuard<'a, T, L> {
+    fn drop(&mut self) {
+        unsafe { self.lock.read_release() }
+    }
+}
+
+pub struct WriteGuard<'a, T, L: ReadWriteLock<T>> {
+    lock: &'a L,
+    phantom: PhantomData<&'a mut T>,
+}
+impl<'a, T, L: ReadWriteLock<T>> Deref for WriteGuard<'a, T, L> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value().get() }
+    }
+}
+impl<'a, T, L: ReadWriteLock<T>> DerefMut for WriteGuard<'a, T, L> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value().get() }
+    }
+}
+impl<'a, T, L: ReadWriteLock<T>> Drop for WriteGuard<'a, T, L> {
+    fn drop(&mut self) {
+        unsafe { self.lock.write_release() }
+    }
+}
+
+const WRITER: usize = 1 << (usize::BITS - 1);
+
+// readers get in whenever there is no writer, a writer waits until there are no readers.
+// state is the number of readers plus the WRITER bit
+pub struct SimpleReadWriteLock<T> {
+    value: UnsafeCell<T>,
+    state: AtomicUsize,
+}
+impl<T> SimpleReadWriteLock<T> {
+    pub fn new(a: T) -> SimpleReadWriteLock<T> {
+        SimpleReadWriteLock {
+            value: UnsafeCell::new(a),
+            state: AtomicUsize::new(0),
+        }
+    }
+}
+impl<T> ReadWriteLock<T> for SimpleReadWriteLock<T> {
+    fn read_acquire(&self) {
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if state & WRITER == 0
+                && self
+                    .state
+                    .compare_exchange(state, state + 1, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            {
+                return;
+            }
+            thread::yield_now()
+        }
+    }
+    unsafe fn read_release(&self) {
+        self.state.fetch_sub(1, Ordering::Release);
+    }
+    fn write_acquire(&self) {
+        while self
+            .state
+            .compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            thread::yield_now()
+        }
+    }
+    unsafe fn write_release(&self) {
+        self.state.store(0, Ordering::Release);
+    }
+    fn value(&self) -> &UnsafeCell<T> {
+        &self.value
+    }
+}
+unsafe impl<T: Send> Send for SimpleReadWriteLock<T> {}
+unsafe impl<T: Send + Sync> Sync for SimpleReadWriteLock<T> {}
+
+// writer preferring, once a writer announced itself no new readers get in
+// and it waits for the ones already inside to leave.
+// acquires is the number of readers that ever got in plus the WRITER bit, releases the ones that left
+pub struct FifoReadWriteLock<T> {
+    value: UnsafeCell<T>,
+    acquires: AtomicUsize,
+    releases: AtomicUsize,
+}
+impl<T> FifoReadWriteLock<T> {
+    pub fn new(a: T) -> FifoReadWriteLock<T> {
+        FifoReadWriteLock {
+            value: UnsafeCell::new(a),
+            acquires: AtomicUsize::new(0),
+            releases: AtomicUsize::new(0),
+        }
+    }
+}
+impl<T> ReadWriteLock<T> for FifoReadWriteLock<T> {
+    fn read_acquire(&self) {
+        loop {
+            let acquires = self.acquires.load(Ordering::Relaxed);
+            if acquires & WRITER == 0
+                && self
+                    .acquires
+                    .compare_exchange(
+                        acquires,
+                        (acquires + 1) & !WRITER,
+                        Ordering::Acquire,
+                        Ordering::Relaxed,
+                    )
+                    .is_ok()
+            {
+                return;
+            }
+            thread::yield_now()
+        }
+    }
+    unsafe fn read_release(&self) {
+        self.releases.fetch_add(1, Ordering::Release);
+    }
+    fn write_acquire(&self) {
+        let mut acquires = self.acquires.load(Ordering::Relaxed);
+        loop {
+            if acquires & WRITER == 0 {
+                match self.acquires.compare_exchange(
+                    acquires,
+                    acquires | WRITER,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break,
+                    Err(a) => acquires = a,
+                }
+            } else {
+                thread::yield_now();
+                acquires = self.acquires.load(Ordering::Relaxed);
+            }
+        }
+        while self.releases.load(Ordering::Acquire) != acquires {
+            thread::yield_now()
+        }
+    }
+    unsafe fn write_release(&self) {
+        self.acquires.fetch_and(!WRITER, Ordering::Release);
+    }
+    fn value(&self) -> &UnsafeCell<T> {
+        &self.value
+    }
+}
+unsafe impl<T: Send> Send for FifoReadWriteLock<T> {}
+unsafe impl<T: Send + Sync> Sync for FifoReadWriteLock<T> {}
+
+// every reader holds its read lock until all of them are inside at the same time
+pub fn concurrent_readers_test<L>(lock: L, readers: usize)
+where
+    L: ReadWriteLock<usize> + Send + Sync + 'static,
+{
+    let lo = std::sync::Arc::new(lock);
+    let barrier = std::sync::Arc::new(std::sync::Barrier::new(readers));
+    let jhs: Vec<_> = (0..readers)
+        .map(|_| {
+            let l = lo.clone();
+            let b = barrier.clone();
+            thread::spawn(move || {
+                let value = l.read();
+                b.wait();
+                *value
+            })
+        })
+        .collect();
+    for jh in jhs {
+        assert_eq!(jh.join().unwrap(), 0)
+    }
+    *lo.write() += 1;
+    assert_eq!(*lo.read(), 1);
+}
+
+// writers update both halves of the pair in two steps, readers must never see them differ
+pub fn writer_exclusion_test<L>(lock: L, writers: usize, readers: usize, iters: usize)
+where
+    L: ReadWriteLock<(usize, usize)> + Send + Sync + 'static,
+{
+    let lo = std::sync::Arc::new(lock);
+    let mut jhs = vec![];
+    for _ in 0..writers {
+        let l = lo.clone();
+        jhs.push(thread::spawn(move || {
+            for _ in 0..iters {
+                let mut pair = l.write();
+                pair.0 += 1;
+                thread::yield_now();
+                pair.1 += 1;
+            }
+        }))
+    }
+    for _ in 0..readers {
+        let l = lo.clone();
+        jhs.push(thread::spawn(move || {
+            for _ in 0..iters {
+                let pair = l.read();
+                assert_eq!(pair.0, pair.1);
+            }
+        }))
+    }
+    for jh in jhs {
+        jh.join().unwrap()
+    }
+    assert_eq!(*lo.read(), (writers * iters, writers * iters));
+}
+
+#[test]
+pub fn simple_rw() {
+    concurrent_readers_test(SimpleReadWriteLock::new(0), 8);
+    writer_exclusion_test(SimpleReadWriteLock::new((0, 0)), 4, 4, 500);
+}
+
+#[test]
+pub fn fifo_rw() {
+    concurrent_readers_test(FifoReadWriteLock::new(0), 8);
+    writer_exclusion_test(FifoReadWriteLock::new((0, 0)), 4, 4, 500);
+}
+
+// a waiting writer keeps new readers out
+#[test]
+pub fn fifo_rw_prefers_writer() {
+    use std::sync::{mpsc::channel, Arc};
+    use std::time::Duration;
+    let lo = Arc::new(FifoReadWriteLock::new(0));
+    let first = lo.read();
+    let (tx, rx) = channel();
+    let tx2 = tx.clone();
+    let l = lo.clone();
+    let writer = thread::spawn(move || {
+        let mut value = l.write();
+        *value += 1;
+        // still holding the lock, so this can't race with the reader's send
+        tx.send("writer").unwrap();
+    });
+    while lo.acquires.load(Ordering::Relaxed) & WRITER == 0 {
+        thread::yield_now()
+    }
+    let l = lo.clone();
+    let reader = thread::spawn(move || {
+        let value = *l.read();
+        tx2.send("reader").unwrap();
+        value
+    });
+    thread::sleep(Duration::from_millis(20));
+    drop(first);
+    assert_eq!(rx.recv().unwrap(), "writer");
+    assert_eq!(rx.recv().unwrap(), "reader");
+    writer.join().unwrap();
+    assert_eq!(reader.join().unwrap(), 1);
+}
+
+// condition variable for the ch2::Lock guards. a waiter queues itself before it releases the lock,
+// so a notify from whoever takes the lock next can't be missed. waiters sleep with thread::park
+struct Waiter {
+    thread: thread::Thread,
+    notified: AtomicBool,
+}
+pub struct Condition {
+    waiters: MCSLock<VecDeque<Arc<Waiter>>>,
+}
+impl Condition {
+    pub fn new() -> Condition {
+        Condition {
+            waiters: MCSLock::new(VecDeque::new()),
+        }
+    }
+    fn enqueue(&self) -> Arc<Waiter> {
+        let waiter = Arc::new(Waiter {
+            thread: thread::current(),
+            notified: AtomicBool::new(false),
+        });
+        self.waiters.lock_any().push_back(waiter.clone());
+        waiter
+    }
+    pub fn wait<T, L: Lock<T>>(&self, guard: &mut LockGuard<'_, T, L>) {
+        let waiter = self.enqueue();
+        guard.unlocked(|| {
+            while !waiter.notified.load(Ordering::Acquire) {
+                thread::park()
+            }
+        })
+    }
+    // returns true if it timed out without being notified
+    pub fn wait_timeout<T, L: Lock<T>>(
+        &self,
+        guard: &mut LockGuard<'_, T, L>,
+        timeout: Duration,
+    ) -> bool {
+        let deadline = Instant::now() + timeout;
+        let waiter = self.enqueue();
+        guard.unlocked(|| {
+            while !waiter.notified.load(Ordering::Acquire) {
+                let now = Instant::now();
+                if now >= deadline {
+                    // a notify might have taken us out of the queue in the meantime
+                    let mut waiters = self.waiters.lock_any();
+                    return match waiters.iter().position(|w| Arc::ptr_eq(w, &waiter)) {
+                        Some(i) => {
+                            waiters.remove(i);
+                            true
+                        }
+                        None => false,
+                    };
+                }
+                thread::park_timeout(deadline - now)
+            }
+            false
+        })
+    }
+    pub fn wait_while<T, L: Lock<T>>(
+        &self,
+        guard: &mut LockGuard<'_, T, L>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) {
+        while condition(guard) {
+            self.wait(guard)
+        }
+    }
+    fn wake(waiter: Arc<Waiter>) {
+        waiter.notified.store(true, Ordering::Release);
+        waiter.thread.unpark()
+    }
+    pub fn notify_one(&self) {
+        let waiter = self.waiters.lock_any().pop_front();
+        if let Some(waiter) = waiter {
+            Condition::wake(waiter)
+        }
+    }
+    pub fn notify_all(&self) {
+        let waiters: Vec<_> = self.waiters.lock_any().drain(..).collect();
+        for waiter in waiters {
+            Condition::wake(waiter)
+        }
+    }
+}
+impl Default for Condition {
+    fn default() -> Condition {
+        Condition::new()
+    }
+}
+
+// monitor example, a fixed size buffer guarded by a ch2::Lock that any thread can take.
+// put waits on not_full and take on not_empty, each signals the other side
+pub struct BoundedBuffer<T, L: Lock<VecDeque<T>>> {
+    lock: L,
+    capacity: usize,
+    not_full: Condition,
+    not_empty: Condition,
+    phantom: PhantomData<T>,
+}
+impl<T, L: Lock<VecDeque<T>>> BoundedBuffer<T, L> {
+    // lock has to protect an empty VecDeque
+    pub fn new(lock: L, capacity: usize) -> BoundedBuffer<T, L> {
+        BoundedBuffer {
+            lock,
+            capacity,
+            not_full: Condition::new(),
+            not_empty: Condition::new(),
+            phantom: PhantomData,
+        }
+    }
+}
+impl<T, L: AnyTidLock<VecDeque<T>>> BoundedBuffer<T, L> {
+    pub fn put(&self, a: T) {
+        let mut items = self.lock.lock_any();
+        let capacity = self.capacity;
+        self.not_full
+            .wait_while(&mut items, |items| items.len() == capacity);
+        items.push_back(a);
+        self.not_empty.notify_one()
+    }
+    pub fn take(&self) -> T {
+        let mut items = self.lock.lock_any();
+        self.not_empty
+            .wait_while(&mut items, |items| items.is_empty());
+        let a = items.pop_front().unwrap();
+        self.not_full.notify_one();
+        a
+    }
+    // None if nothing arrived in time
+    pub fn take_timeout(&self, timeout: Duration) -> Option<T> {
+        let deadline = Instant::now() + timeout;
+        let mut items = self.lock.lock_any();
+        while items.is_empty() {
+            let now = Instant::now();
+            if now >= deadline || self.not_empty.wait_timeout(&mut items, deadline - now) {
+                break;
+            }
+        }
+        let a = items.pop_front();
+        if a.is_some() {
+            self.not_full.notify_one();
+        }
+        a
+    }
+}
+
+#[test]
+pub fn condition_wait() {
+    let lo = Arc::new((super::ch7::TTASLock::new(false), Condition::new()));
+    let l = lo.clone();
+    let jh = thread::spawn(move || {
+        let mut ready = l.0.lock_any();
+        l.1.wait_while(&mut ready, |ready| !*ready);
+    });
+    thread::sleep(Duration::from_millis(10));
+    *lo.0.lock_any() = true;
+    lo.1.notify_all();
+    jh.join().unwrap();
+
+    let mut ready = lo.0.lock_any();
+    assert!(lo.1.wait_timeout(&mut ready, Duration::from_millis(10)));
+}
+
+// producers and consumers through a two slot buffer, every item arrives exactly once
+#[test]
+pub fn bounded_buffer() {
+    let buffer = Arc::new(BoundedBuffer::new(MCSLock::new(VecDeque::new()), 2));
+    let mut producers = vec![];
+    for p in 0..4 {
+        let b = buffer.clone();
+        producers.push(thread::spawn(move || {
+            for i in 0..250 {
+                b.put(p * 250 + i)
+            }
+        }))
+    }
+    let mut consumers = vec![];
+    for _ in 0..4 {
+        let b = buffer.clone();
+        consumers.push(thread::spawn(move || {
+            (0..250).map(|_| b.take()).collect::<Vec<usize>>()
+        }))
+    }
+    for jh in producers {
+        jh.join().unwrap()
+    }
+    let mut all: Vec<_> = consumers
+        .into_iter()
+        .flat_map(|jh| jh.join().unwrap())
+        .collect();
+    all.sort_unstable();
+    assert_eq!(all, (0..1000).collect::<Vec<_>>());
+    assert_eq!(buffer.take_timeout(Duration::from_millis(10)), None);
+}
+
+// makes any ch2::Lock reentrant. the owning thread can lock again and only the last guard
+// releases the inner lock. guards only give shared access since several of them can be alive,
+// use Cell or RefCell inside for mutation
+pub struct ReentrantLock<T, L: Lock<T>> {
+    lock: L,
+    // current_thread() of the owner, 0 when free
+    owner: AtomicUsize,
+    // only touched by the owner
+    count: Cell<usize>,
+    token: UnsafeCell<Option<L::Token>>,
+    phantom: PhantomData<T>,
+}
+pub struct ReentrantGuard<'a, T, L: Lock<T>> {
+    lock: &'a ReentrantLock<T, L>,
+    // the hold count belongs to this thread
+    phantom: PhantomData<*const ()>,
+}
+
+// unique for every running thread and never 0
+fn current_thread() -> usize {
+    thread_local!(static KEY: u8 = const { 0 });
+    KEY.with(|k| k as *const u8 as usize)
+}
+
+impl<T, L: Lock<T>> ReentrantLock<T, L> {
+    pub fn new(lock: L) -> ReentrantLock<T, L> {
+        ReentrantLock {
+            lock,
+            owner: AtomicUsize::new(0),
+            count: Cell::new(0),
+            token: UnsafeCell::new(None),
+            phantom: PhantomData,
+        }
+    }
+    // tid follows the ch2::Lock rules for the inner lock, except that the owner may lock again
+    // with the same tid
+    unsafe fn lock_tid(&self, tid: usize) -> ReentrantGuard<'_, T, L> {
+        let me = current_thread();
+        if self.owner.load(Ordering::Relaxed) != me {
+            let token = self.lock.acquire(tid);
+            *self.token.get() = Some(token);
+            self.owner.store(me, Ordering::Relaxed);
+        }
+        self.count.set(self.count.get() + 1);
+        ReentrantGuard {
+            lock: self,
+            phantom: PhantomData,
+        }
+    }
+    pub fn hold_count(&self) -> usize {
+        if self.owner.load(Ordering::Relaxed) == current_thread() {
+            self.count.get()
+        } else {
+            0
+        }
+    }
+}
+impl<T, L: AnyTidLock<T>> ReentrantLock<T, L> {
+    pub fn lock(&self) -> ReentrantGuard<'_, T, L> {
+        unsafe { self.lock_tid(0) }
+    }
+}
+impl<'a, T, L: Lock<T>> Deref for ReentrantGuard<'a, T, L> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.lock.value().get() }
+    }
+}
+impl<'a, T, L: Lock<T>> Drop for ReentrantGuard<'a, T, L> {
+    fn drop(&mut self) {
+        let lock = self.lock;
+        lock.count.set(lock.count.get() - 1);
+        if lock.count.get() == 0 {
+            lock.owner.store(0, Ordering::Relaxed);
+            let token = unsafe { (*lock.token.get()).take().unwrap() };
+            unsafe { lock.lock.release(token) }
+        }
+    }
+}
+unsafe impl<T: Send, L: Lock<T> + Send> Send for ReentrantLock<T, L> {}
+unsafe impl<T: Send, L: Lock<T> + Sync> Sync for ReentrantLock<T, L> {}
+
+fn visit<L: AnyTidLock<Cell<usize>>>(lock: &ReentrantLock<Cell<usize>, L>, depth: usize) {
+    let count = lock.lock();
+    count.set(count.get() + 1);
+    if depth > 0 {
+        visit(lock, depth - 1)
+    }
+}
+
+// a recursive visitor that deadlocked on the plain spin locks
+#[test]
+pub fn reentrant() {
+    use super::ch7::{ALock, TTASLock};
+    let ttas = ReentrantLock::new(TTASLock::new(Cell::new(0)));
+    visit(&ttas, 10);
+    assert_eq!(ttas.lock().get(), 11);
+    assert_eq!(ttas.hold_count(), 0);
+
+    let alock = Arc::new(ReentrantLock::new(ALock::new(Cell::new(0), 8)));
+    let jhs: Vec<_> = (0..8)
+        .map(|_| {
+            let l = alock.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    let outer = l.lock();
+                    visit(&l, 3);
+                    assert_eq!(l.hold_count(), 1);
+                    drop(outer)
+                }
+            })
+        })
+        .collect();
+    for jh in jhs {
+        jh.join().unwrap()
+    }
+    assert_eq!(alock.lock().get(), 8 * 100 * 4);
+}
+
+// counting semaphore, the permits are guarded by a ch2::Lock that any thread can take and
+// waiters sleep on a Condition
+pub struct Semaphore<L: Lock<usize>> {
+    permits: L,
+    available: Condition,
+}
+impl<L: Lock<usize>> Semaphore<L> {
+    // permits holds the initial number of permits
+    pub fn new(permits: L) -> Semaphore<L> {
+        Semaphore {
+            permits,
+            available: Condition::new(),
+        }
+    }
+}
+impl<L: AnyTidLock<usize>> Semaphore<L> {
+    pub fn acquire(&self) {
+        let mut permits = self.permits.lock_any();
+        self.available.wait_while(&mut permits, |p| *p == 0);
+        *permits -= 1;
+    }
+    pub fn try_acquire(&self) -> bool {
+        let mut permits = self.permits.lock_any();
+        if *permits == 0 {
+            return false;
+        }
+        *permits -= 1;
+        true
+    }
+    // false if no permit became available in time
+    pub fn acquire_timeout(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut permits = self.permits.lock_any();
+        while *permits == 0 {
+            let now = Instant::now();
+            if now >= deadline || self.available.wait_timeout(&mut permits, deadline - now) {
+                return false;
+            }
+        }
+        *permits -= 1;
+        true
+    }
+    pub fn release(&self, n: usize) {
+        *self.permits.lock_any() += n;
+        for _ in 0..n {
+            self.available.notify_one()
+        }
+    }
+    pub fn available_permits(&self) -> usize {
+        *self.permits.lock_any()
+    }
+}
+
+// never more than 3 workers inside at the same time
+#[test]
+pub fn semaphore() {
+    let sem = Arc::new(Semaphore::new(MCSLock::new(3)));
+    let inside = Arc::new(AtomicUsize::new(0));
+    let max = Arc::new(AtomicUsize::new(0));
+    let jhs: Vec<_> = (0..10)
+        .map(|_| {
+            let (s, inside, max) = (sem.clone(), inside.clone(), max.clone());
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    s.acquire();
+                    let now = inside.fetch_add(1, Ordering::SeqCst) + 1;
+                    max.fetch_max(now, Ordering::SeqCst);
+                    thread::yield_now();
+                    inside.fetch_sub(1, Ordering::SeqCst);
+                    s.release(1);
+                }
+            })
+        })
+        .collect();
+    for jh in jhs {
+        jh.join().unwrap()
+    }
+    assert!(max.load(Ordering::SeqCst) <= 3);
+    assert_eq!(sem.available_permits(), 3);
+}
+
+#[test]
+pub fn semaphore_timeout() {
+    let sem = Arc::new(Semaphore::new(MCSLock::new(1)));
+    assert!(sem.try_acquire());
+    assert!(!sem.try_acquire());
+    assert!(!sem.acquire_timeout(Duration::from_millis(10)));
+
+    // release(2) lets both waiters through
+    let jhs: Vec<_> = (0..2)
+        .map(|_| {
+            let s = sem.clone();
+            thread::spawn(move || s.acquire_timeout(Duration::from_secs(10)))
+        })
+        .collect();
+    thread::sleep(Duration::from_millis(10));
+    sem.release(2);
+    for jh in jhs {
+        assert!(jh.join().unwrap())
+    }
+    assert_eq!(sem.available_permits(), 0);
+}
@@ -1,13 +1,24 @@
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(test)]
+use super::epoch::Counted;
+use super::epoch::{Collector, Guard};
+use super::tagged::AtomicMarkablePtr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
 use std::{
     cell::{Cell, UnsafeCell},
     fmt::Debug,
-    ops::Deref,
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, MutexGuard},
     thread,
+    time::{Duration, Instant},
 };
 use thread::JoinHandle;
 
+// the set interface shared by the ch9 lists, add and remove tell whether they changed the set
+pub trait ConcurrentSet<T> {
+    fn add(&self, a: T) -> bool;
+    fn remove(&self, a: &T) -> bool;
+    fn contains(&self, a: &T) -> bool;
+}
+
 pub struct List<T> {
     head: Option<Box<Node<T>>>,
 }
@@ -36,21 +47,35 @@ impl<T> List<T> {
             self.add(a)
         }
     }
-    pub fn remove(&mut self, a: T)
+    pub fn remove(&mut self, a: &T) -> bool
     where
-        T: PartialOrd + Debug,
+        T: PartialOrd,
     {
         if let Some(x) = &mut self.head {
-            if x.0 < a {
-                x.1.remove(a);
-            } else if x.0 == a {
+            if x.0 < *a {
+                x.1.remove(a)
+            } else if x.0 == *a {
                 self.pop();
+                true
             } else {
-                return;
+                false
             }
         } else {
-            return;
+            false
+        }
+    }
+    pub fn contains(&self, a: &T) -> bool
+    where
+        T: PartialOrd,
+    {
+        let mut it = &self.head;
+        while let Some(node) = it {
+            if node.0 >= *a {
+                return node.0 == *a;
+            }
+            it = &node.1.head;
         }
+        false
     }
     pub fn pop(&mut self) -> Option<T> {
         if let Some(x) = self.head.take() {
@@ -93,7 +118,7 @@ impl<T> Drop for List<T> {
         }
     }
 }
-struct CoarseList<T> {
+pub struct CoarseList<T> {
     head: Mutex<List<T>>,
 }
 impl<T> CoarseList<T> {
@@ -102,17 +127,24 @@ impl<T> CoarseList<T> {
             head: Mutex::new(List::new()),
         }
     }
+    // unordered, use add_ordered or ConcurrentSet::add to keep the set sorted
     pub fn add(&self, a: T) {
         let mut head1 = self.head.lock().unwrap();
         head1.add(a)
     }
-    pub fn remove(&self, a: T)
+    pub fn remove(&self, a: &T) -> bool
     where
-        T: PartialOrd + Debug,
+        T: PartialOrd,
     {
         let mut head1 = self.head.lock().unwrap();
 
-        head1.remove(a);
+        head1.remove(a)
+    }
+    pub fn contains(&self, a: &T) -> bool
+    where
+        T: PartialOrd,
+    {
+        self.head.lock().unwrap().contains(a)
     }
     pub fn pop(&self) -> Option<T> {
         let mut head1 = self.head.lock().unwrap();
@@ -136,6 +168,28 @@ impl<T> CoarseList<T> {
 unsafe impl<T> Send for CoarseList<T> {}
 unsafe impl<T> Sync for CoarseList<T> {}
 
+impl<T: PartialOrd> ConcurrentSet<T> for CoarseList<T> {
+    fn add(&self, a: T) -> bool {
+        let mut head1 = self.head.lock().unwrap();
+        if head1.contains(&a) {
+            return false;
+        }
+        head1.add_ordered(a);
+        true
+    }
+    fn remove(&self, a: &T) -> bool {
+        CoarseList::remove(self, a)
+    }
+    fn contains(&self, a: &T) -> bool {
+        CoarseList::contains(self, a)
+    }
+}
+impl<T> Default for CoarseList<T> {
+    fn default() -> CoarseList<T> {
+        CoarseList::new()
+    }
+}
+
 #[test]
 pub fn coarse_test() {
     let e = Arc::new(CoarseList::<usize>::new());
@@ -145,7 +199,7 @@ pub fn coarse_test() {
         let tid = thread::spawn(move || {
             for i in 1..1000 {
                 l.add_ordered(i);
-                l.remove(i)
+                l.remove(&i);
             }
         });
         jhs.push(tid)
@@ -158,53 +212,127 @@ pub fn coarse_test() {
     println!("value is  {:?}", e.len())
 }
 
+// fine grained sorted set, every node has its own lock and a traversal holds at most two of them,
+// taking the next one before letting go of the previous (hand over hand).
+// the lock of a node guards its next pointer. head and tail are sentinels, the tail has no key
 pub struct FGList<T> {
-    head: Mutex<Option<Box<FGNode<T>>>>,
+    head: *mut FGNode<T>,
 }
-struct FGNode<T>(T, FGList<T>);
-unsafe impl<T> Send for FGList<T> {}
-unsafe impl<T> Sync for FGList<T> {}
+struct FGNode<T> {
+    key: Option<T>,
+    next: Mutex<*mut FGNode<T>>,
+}
+unsafe impl<T: Send> Send for FGList<T> {}
+unsafe impl<T: Send> Sync for FGList<T> {}
 
-impl<T> FGList<T> {
+impl<T> FGNode<T> {
+    fn alloc(key: Option<T>, next: *mut FGNode<T>) -> *mut FGNode<T> {
+        Box::into_raw(Box::new(FGNode {
+            key,
+            next: Mutex::new(next),
+        }))
+    }
+}
+
+type Link<'a, T> = MutexGuard<'a, *mut FGNode<T>>;
+
+impl<T: PartialOrd> FGList<T> {
     pub fn new() -> FGList<T> {
+        let tail = FGNode::alloc(None, std::ptr::null_mut());
         FGList {
-            head: Mutex::new(None),
+            head: FGNode::alloc(None, tail),
         }
     }
-    pub fn add(&self, a: T) {
-        let mut head1 = self.head.lock().unwrap();
-        let oldhead = head1.take();
-        *head1 = Some(Box::new(FGNode(
-            a,
-            FGList {
-                head: Mutex::new(oldhead),
-            },
-        )));
+    // returns the locked pred and curr with pred < a <= curr
+    fn find(&self, a: &T) -> (Link<'_, T>, &FGNode<T>, Link<'_, T>) {
+        let mut pred = unsafe { &*self.head }.next.lock().unwrap();
+        let mut curr = unsafe { &**pred };
+        let mut next = curr.next.lock().unwrap();
+        while matches!(&curr.key, Some(k) if k < a) {
+            pred = next;
+            curr = unsafe { &**pred };
+            next = curr.next.lock().unwrap();
+        }
+        (pred, curr, next)
     }
-    pub fn add_ordered(&self, a: T)
-    where
-        T: PartialOrd,
-    {
-        let l = self.head.lock().unwrap();
-        match l.deref() {
-            Some(node) if node.0 < a => {
-                node.1.add_ordered(a);
-                std::mem::drop(l)
+    pub fn add(&self, a: T) -> bool {
+        let (mut pred, curr, _next) = self.find(&a);
+        if curr.key.as_ref() == Some(&a) {
+            return false;
+        }
+        *pred = FGNode::alloc(Some(a), *pred);
+        true
+    }
+    pub fn remove(&self, a: &T) -> bool {
+        let (mut pred, curr, next) = self.find(a);
+        if curr.key.as_ref() != Some(a) {
+            return false;
+        }
+        let removed = *pred;
+        *pred = *next;
+        // whoever wants to reach curr has to lock pred first, which we still hold
+        drop(next);
+        unsafe { drop(Box::from_raw(removed)) };
+        true
+    }
+    pub fn contains(&self, a: &T) -> bool {
+        let (_pred, curr, _next) = self.find(a);
+        curr.key.as_ref() == Some(a)
+    }
+    pub fn len(&self) -> usize {
+        let mut count = 0;
+        let mut pred = unsafe { &*self.head }.next.lock().unwrap();
+        loop {
+            let curr = unsafe { &**pred };
+            if curr.key.is_none() {
+                return count;
             }
-            _ => self.add(a),
+            count += 1;
+            pred = curr.next.lock().unwrap();
         }
     }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 
     pub fn print(&self)
     where
         T: Debug,
     {
-        if let Some(node) = &*self.head.lock().unwrap() {
-            print!("{:?}-", node.0);
-            node.1.print();
+        let mut pred = unsafe { &*self.head }.next.lock().unwrap();
+        while let Some(key) = &unsafe { &**pred }.key {
+            print!("{:?}-", key);
+            pred = unsafe { &**pred }.next.lock().unwrap();
         }
+        println!()
     }
 }
+impl<T: PartialOrd> ConcurrentSet<T> for FGList<T> {
+    fn add(&self, a: T) -> bool {
+        FGList::add(self, a)
+    }
+    fn remove(&self, a: &T) -> bool {
+        FGList::remove(self, a)
+    }
+    fn contains(&self, a: &T) -> bool {
+        FGList::contains(self, a)
+    }
+}
+impl<T: PartialOrd> Default for FGList<T> {
+    fn default() -> FGList<T> {
+        FGList::new()
+    }
+}
+impl<T> Drop for FGList<T> {
+    fn drop(&mut self) {
+        let mut node = self.head;
+        while !node.is_null() {
+            let b = unsafe { Box::from_raw(node) };
+            node = *b.next.lock().unwrap();
+        }
+    }
+}
+
 #[test]
 pub fn fine_test() {
     let e = Arc::new(FGList::<usize>::new());
@@ -212,8 +340,9 @@ pub fn fine_test() {
     for _ in 0..4 {
         let l = e.clone();
         let tid = thread::spawn(move || {
-            for i in 1..10 {
-                l.add_ordered(i);
+            for i in 1..1000 {
+                l.add(i);
+                l.remove(&i);
             }
         });
         jhs.push(tid)
@@ -222,5 +351,798 @@ pub fn fine_test() {
     for jh in jhs {
         jh.join().unwrap()
     }
+    assert_eq!(e.len(), 0);
+}
+
+// every thread adds its own range and removes the odd numbers again
+#[test]
+pub fn fine_disjoint_test() {
+    let e = Arc::new(FGList::<usize>::new());
+    let mut jhs = vec![];
+    for t in 0..4 {
+        let l = e.clone();
+        jhs.push(thread::spawn(move || {
+            for i in (t * 100..(t + 1) * 100).rev() {
+                assert!(l.add(i));
+                assert!(!l.add(i));
+            }
+            for i in (t * 100..(t + 1) * 100).filter(|i| i % 2 == 1) {
+                assert!(l.remove(&i));
+                assert!(!l.remove(&i));
+            }
+        }))
+    }
+    for jh in jhs {
+        jh.join().unwrap()
+    }
+    assert_eq!(e.len(), 200);
+    assert!((0..400).all(|i| e.contains(&i) == (i % 2 == 0)));
     e.print();
 }
+
+// optimistic sorted set, traverses without locks, then locks pred and curr and checks
+// that pred is still reachable and still points to curr before changing anything.
+// a traversal can still be standing on a removed node, so every operation is pinned
+// and removed nodes are retired to the collector
+pub struct OptimisticList<T> {
+    head: *mut ONode<T>,
+    collector: Collector,
+}
+struct ONode<T> {
+    key: Option<T>,
+    next: AtomicPtr<ONode<T>>,
+    lock: Mutex<()>,
+}
+unsafe impl<T: Send> Send for OptimisticList<T> {}
+unsafe impl<T: Send + Sync> Sync for OptimisticList<T> {}
+
+impl<T> ONode<T> {
+    fn alloc(key: Option<T>, next: *mut ONode<T>) -> *mut ONode<T> {
+        Box::into_raw(Box::new(ONode {
+            key,
+            next: AtomicPtr::new(next),
+            lock: Mutex::new(()),
+        }))
+    }
+    fn next(&self) -> &ONode<T> {
+        unsafe { &*self.next.load(Ordering::Acquire) }
+    }
+}
+// keys only grow towards the tail, which has no key
+fn before<T: PartialOrd>(key: &Option<T>, a: &T) -> bool {
+    matches!(key, Some(k) if k < a)
+}
+
+impl<T: PartialOrd> OptimisticList<T> {
+    pub fn new() -> OptimisticList<T> {
+        let tail = ONode::alloc(None, std::ptr::null_mut());
+        OptimisticList {
+            head: ONode::alloc(None, tail),
+            collector: Collector::new(),
+        }
+    }
+    // pred is still reachable from head and still points to curr
+    fn validate(&self, pred: &ONode<T>, curr: &ONode<T>) -> bool {
+        let mut node = unsafe { &*self.head };
+        loop {
+            if std::ptr::eq(node, pred) {
+                return std::ptr::eq(pred.next(), curr);
+            }
+            node = node.next();
+            match (&node.key, &pred.key) {
+                (Some(k), Some(p)) if k <= p => (),
+                _ => return false,
+            }
+        }
+    }
+    // returns pred and curr locked, pred < a <= curr
+    fn find<'g>(
+        &self,
+        a: &T,
+        _guard: &'g Guard,
+    ) -> (&'g ONode<T>, &'g ONode<T>, [MutexGuard<'g, ()>; 2]) {
+        loop {
+            let mut pred = unsafe { &*self.head };
+            let mut curr = pred.next();
+            while before(&curr.key, a) {
+                pred = curr;
+                curr = curr.next();
+            }
+            let locks = [pred.lock.lock().unwrap(), curr.lock.lock().unwrap()];
+            if self.validate(pred, curr) {
+                return (pred, curr, locks);
+            }
+        }
+    }
+    pub fn add(&self, a: T) -> bool {
+        let guard = self.collector.pin();
+        let (pred, curr, _locks) = self.find(&a, &guard);
+        if curr.key.as_ref() == Some(&a) {
+            return false;
+        }
+        let node = ONode::alloc(Some(a), curr as *const _ as *mut _);
+        pred.next.store(node, Ordering::Release);
+        true
+    }
+    pub fn remove(&self, a: &T) -> bool {
+        let guard = self.collector.pin();
+        let (pred, curr, _locks) = self.find(a, &guard);
+        if curr.key.as_ref() != Some(a) {
+            return false;
+        }
+        pred.next
+            .store(curr.next.load(Ordering::Acquire), Ordering::Release);
+        // curr stays valid until the guard is gone, the lock on it is released before that
+        unsafe { guard.defer_destroy(curr as *const _ as *mut ONode<T>) };
+        true
+    }
+    pub fn contains(&self, a: &T) -> bool {
+        let guard = self.collector.pin();
+        let (_, curr, _locks) = self.find(a, &guard);
+        curr.key.as_ref() == Some(a)
+    }
+    pub fn len(&self) -> usize {
+        let _guard = self.collector.pin();
+        let mut count = 0;
+        let mut node = unsafe { &*self.head }.next();
+        while node.key.is_some() {
+            count += 1;
+            node = node.next();
+        }
+        count
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+impl<T: PartialOrd> ConcurrentSet<T> for OptimisticList<T> {
+    fn add(&self, a: T) -> bool {
+        OptimisticList::add(self, a)
+    }
+    fn remove(&self, a: &T) -> bool {
+        OptimisticList::remove(self, a)
+    }
+    fn contains(&self, a: &T) -> bool {
+        OptimisticList::contains(self, a)
+    }
+}
+impl<T: PartialOrd> Default for OptimisticList<T> {
+    fn default() -> OptimisticList<T> {
+        OptimisticList::new()
+    }
+}
+// the removed nodes are in the collector
+impl<T> Drop for OptimisticList<T> {
+    fn drop(&mut self) {
+        let mut node = self.head;
+        while !node.is_null() {
+            let b = unsafe { Box::from_raw(node) };
+            node = b.next.load(Ordering::Relaxed);
+        }
+    }
+}
+
+#[test]
+pub fn optimistic_test() {
+    let e = Arc::new(OptimisticList::<usize>::new());
+    let mut jhs = vec![];
+    for t in 0..4 {
+        let l = e.clone();
+        jhs.push(thread::spawn(move || {
+            for i in 1..1000 {
+                l.add(i);
+                l.remove(&(i + t));
+            }
+        }))
+    }
+    for jh in jhs {
+        jh.join().unwrap()
+    }
+    for i in 1..1003 {
+        e.remove(&i);
+    }
+    assert_eq!(e.len(), 0);
+}
+
+// conformance suite every ConcurrentSet has to pass, plug a new set in with one more test below
+pub fn set_test<S>(threads: usize, ops: usize)
+where
+    S: ConcurrentSet<usize> + Default + Send + Sync + 'static,
+{
+    set_sequential_test(&S::default());
+    set_stress_test(S::default(), threads, ops);
+}
+
+// plain set semantics from a single thread
+pub fn set_sequential_test<S: ConcurrentSet<usize>>(set: &S) {
+    assert!(!set.contains(&5));
+    assert!(!set.remove(&5));
+    assert!(set.add(5));
+    assert!(!set.add(5));
+    assert!(set.contains(&5));
+    assert!(set.remove(&5));
+    assert!(!set.remove(&5));
+    assert!(!set.contains(&5));
+    // out of order inserts, the lists have to keep their keys sorted
+    for i in (0..100).map(|i| i * 37 % 100) {
+        assert!(set.add(i));
+    }
+    for i in (0..100).filter(|i| i % 2 == 1) {
+        assert!(set.remove(&i));
+    }
+    assert!((0..100).all(|i| set.contains(&i) == (i % 2 == 0)));
+    for i in 0..100 {
+        assert_eq!(set.remove(&i), i % 2 == 0);
+    }
+    assert!((0..100).all(|i| !set.contains(&i)));
+}
+
+// every thread owns the keys equal to its id mod threads and checks each answer against
+// its own sequential model, while all of them also fight over the shared keys 0..8.
+// a thread always removes a shared key right after adding it, so in the end they are all gone
+// and every successful add was matched by exactly one successful remove
+pub fn set_stress_test<S>(set: S, threads: usize, ops: usize)
+where
+    S: ConcurrentSet<usize> + Send + Sync + 'static,
+{
+    use rand::Rng;
+    use std::collections::BTreeSet;
+    const SHARED: usize = 8;
+    let set = Arc::new(set);
+    let jhs: Vec<_> = (0..threads)
+        .map(|t| {
+            let s = set.clone();
+            thread::spawn(move || {
+                let mut rng = rand::thread_rng();
+                let mut model = BTreeSet::new();
+                let (mut adds, mut removes) = (0, 0);
+                for _ in 0..ops {
+                    let key = SHARED + rng.gen_range(0, 32) * threads + t;
+                    match rng.gen_range(0, 4) {
+                        0 => assert_eq!(s.add(key), model.insert(key)),
+                        1 => assert_eq!(s.remove(&key), model.remove(&key)),
+                        2 => assert_eq!(s.contains(&key), model.contains(&key)),
+                        _ => {
+                            let key = rng.gen_range(0, SHARED);
+                            adds += s.add(key) as usize;
+                            removes += s.remove(&key) as usize;
+                        }
+                    }
+                }
+                (model, adds, removes)
+            })
+        })
+        .collect();
+    let mut expected = BTreeSet::new();
+    let (mut adds, mut removes) = (0, 0);
+    for jh in jhs {
+        let (model, a, r) = jh.join().unwrap();
+        expected.extend(model);
+        adds += a;
+        removes += r;
+    }
+    assert_eq!(adds, removes);
+    let max = SHARED + 32 * threads;
+    assert!((0..max).all(|i| set.contains(&i) == expected.contains(&i)));
+}
+
+#[test]
+pub fn coarse_set() {
+    set_test::<CoarseList<usize>>(4, 2000);
+}
+#[test]
+pub fn fine_set() {
+    set_test::<FGList<usize>>(4, 2000);
+}
+#[test]
+pub fn optimistic_set() {
+    set_test::<OptimisticList<usize>>(4, 2000);
+}
+#[test]
+pub fn lazy_set() {
+    set_test::<LazyList<usize>>(4, 2000);
+}
+#[test]
+pub fn lock_free_set() {
+    set_test::<LockFreeList<usize>>(4, 2000);
+}
+
+// threads do a mix of operations on random keys in 0..range, contains_percent of them lookups
+pub fn set_bench<S>(
+    set: S,
+    threads: usize,
+    ops: usize,
+    range: usize,
+    contains_percent: usize,
+) -> Duration
+where
+    S: ConcurrentSet<usize> + Send + Sync + 'static,
+{
+    use rand::Rng;
+    let set = Arc::new(set);
+    let now = Instant::now();
+    let jhs: Vec<_> = (0..threads)
+        .map(|_| {
+            let s = set.clone();
+            thread::spawn(move || {
+                let mut rng = rand::thread_rng();
+                for _ in 0..ops {
+                    let key = rng.gen_range(0, range);
+                    let op = rng.gen_range(0, 100);
+                    if op < contains_percent {
+                        s.contains(&key);
+                    } else if op % 2 == 0 {
+                        s.add(key);
+                    } else {
+                        s.remove(&key);
+                    }
+                }
+            })
+        })
+        .collect();
+    for jh in jhs {
+        jh.join().unwrap()
+    }
+    now.elapsed()
+}
+
+#[test]
+pub fn set_comparison() {
+    for contains_percent in &[0, 90] {
+        println!(
+            "coarse {:?} fine {:?} optimistic {:?} lazy {:?} lock-free {:?} ({}% contains)",
+            set_bench(CoarseList::new(), 4, 2000, 200, *contains_percent),
+            set_bench(FGList::new(), 4, 2000, 200, *contains_percent),
+            set_bench(OptimisticList::new(), 4, 2000, 200, *contains_percent),
+            set_bench(LazyList::new(), 4, 2000, 200, *contains_percent),
+            set_bench(LockFreeList::new(), 4, 2000, 200, *contains_percent),
+            contains_percent
+        );
+    }
+}
+
+// lazy sorted set, like OptimisticList but remove first marks the node and then unlinks it,
+// so validation doesn't need a second traversal and contains takes no locks at all.
+// removed nodes go through the collector for the same reason as in OptimisticList
+pub struct LazyList<T> {
+    head: *mut LazyNode<T>,
+    collector: Collector,
+}
+struct LazyNode<T> {
+    key: Option<T>,
+    next: AtomicPtr<LazyNode<T>>,
+    lock: Mutex<()>,
+    marked: AtomicBool,
+}
+unsafe impl<T: Send> Send for LazyList<T> {}
+unsafe impl<T: Send + Sync> Sync for LazyList<T> {}
+
+impl<T> LazyNode<T> {
+    fn alloc(key: Option<T>, next: *mut LazyNode<T>) -> *mut LazyNode<T> {
+        Box::into_raw(Box::new(LazyNode {
+            key,
+            next: AtomicPtr::new(next),
+            lock: Mutex::new(()),
+            marked: AtomicBool::new(false),
+        }))
+    }
+    fn next(&self) -> &LazyNode<T> {
+        unsafe { &*self.next.load(Ordering::Acquire) }
+    }
+}
+
+impl<T: PartialOrd> LazyList<T> {
+    pub fn new() -> LazyList<T> {
+        let tail = LazyNode::alloc(None, std::ptr::null_mut());
+        LazyList {
+            head: LazyNode::alloc(None, tail),
+            collector: Collector::new(),
+        }
+    }
+    fn traverse<'g>(&self, a: &T, _guard: &'g Guard) -> (&'g LazyNode<T>, &'g LazyNode<T>) {
+        let mut pred = unsafe { &*self.head };
+        let mut curr = pred.next();
+        while before(&curr.key, a) {
+            pred = curr;
+            curr = curr.next();
+        }
+        (pred, curr)
+    }
+    // returns pred and curr locked, both unmarked and adjacent, pred < a <= curr
+    fn find<'g>(
+        &self,
+        a: &T,
+        guard: &'g Guard,
+    ) -> (&'g LazyNode<T>, &'g LazyNode<T>, [MutexGuard<'g, ()>; 2]) {
+        loop {
+            let (pred, curr) = self.traverse(a, guard);
+            let locks = [pred.lock.lock().unwrap(), curr.lock.lock().unwrap()];
+            if !pred.marked.load(Ordering::Acquire)
+                && !curr.marked.load(Ordering::Acquire)
+                && std::ptr::eq(pred.next(), curr)
+            {
+                return (pred, curr, locks);
+            }
+        }
+    }
+    pub fn add(&self, a: T) -> bool {
+        let guard = self.collector.pin();
+        let (pred, curr, _locks) = self.find(&a, &guard);
+        if curr.key.as_ref() == Some(&a) {
+            return false;
+        }
+        let node = LazyNode::alloc(Some(a), curr as *const _ as *mut _);
+        pred.next.store(node, Ordering::Release);
+        true
+    }
+    pub fn remove(&self, a: &T) -> bool {
+        let guard = self.collector.pin();
+        let (pred, curr, _locks) = self.find(a, &guard);
+        if curr.key.as_ref() != Some(a) {
+            return false;
+        }
+        curr.marked.store(true, Ordering::Release);
+        pred.next
+            .store(curr.next.load(Ordering::Acquire), Ordering::Release);
+        // curr stays valid until the guard is gone, the lock on it is released before that
+        unsafe { guard.defer_destroy(curr as *const _ as *mut LazyNode<T>) };
+        true
+    }
+    // wait free, a marked node is already out of the set even if it is still linked
+    pub fn contains(&self, a: &T) -> bool {
+        let guard = self.collector.pin();
+        let (_, curr) = self.traverse(a, &guard);
+        curr.key.as_ref() == Some(a) && !curr.marked.load(Ordering::Acquire)
+    }
+    pub fn len(&self) -> usize {
+        let _guard = self.collector.pin();
+        let mut count = 0;
+        let mut node = unsafe { &*self.head }.next();
+        while node.key.is_some() {
+            count += 1;
+            node = node.next();
+        }
+        count
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+impl<T: PartialOrd> ConcurrentSet<T> for LazyList<T> {
+    fn add(&self, a: T) -> bool {
+        LazyList::add(self, a)
+    }
+    fn remove(&self, a: &T) -> bool {
+        LazyList::remove(self, a)
+    }
+    fn contains(&self, a: &T) -> bool {
+        LazyList::contains(self, a)
+    }
+}
+impl<T: PartialOrd> Default for LazyList<T> {
+    fn default() -> LazyList<T> {
+        LazyList::new()
+    }
+}
+// the removed nodes are in the collector
+impl<T> Drop for LazyList<T> {
+    fn drop(&mut self) {
+        let mut node = self.head;
+        while !node.is_null() {
+            let b = unsafe { Box::from_raw(node) };
+            node = b.next.load(Ordering::Relaxed);
+        }
+    }
+}
+
+// writers churn the odd keys while readers keep finding the even ones, which are never removed
+#[test]
+pub fn lazy_test() {
+    let e = Arc::new(LazyList::<usize>::new());
+    for i in (0..200).step_by(2) {
+        e.add(i);
+    }
+    let mut jhs = vec![];
+    for _ in 0..3 {
+        let l = e.clone();
+        jhs.push(thread::spawn(move || {
+            for _ in 0..10 {
+                for i in (1..200).step_by(2) {
+                    l.add(i);
+                    l.remove(&i);
+                }
+            }
+        }))
+    }
+    for _ in 0..3 {
+        let l = e.clone();
+        jhs.push(thread::spawn(move || {
+            for _ in 0..10 {
+                assert!((0..200).step_by(2).all(|i| l.contains(&i)));
+            }
+        }))
+    }
+    for jh in jhs {
+        jh.join().unwrap()
+    }
+    assert_eq!(e.len(), 100);
+    assert!((1..200).step_by(2).all(|i| !e.contains(&i)));
+}
+
+// harris-michael lock-free sorted set. the mark on a node's next pointer means the node
+// is removed, so marking and unlinking can't race with an insert behind the node.
+// find snips out the marked nodes it passes, whoever unlinks a node retires it to the collector
+pub struct LockFreeList<T> {
+    head: *mut LFNode<T>,
+    collector: Collector,
+}
+struct LFNode<T> {
+    key: Option<T>,
+    next: AtomicMarkablePtr<LFNode<T>>,
+}
+unsafe impl<T: Send> Send for LockFreeList<T> {}
+unsafe impl<T: Send + Sync> Sync for LockFreeList<T> {}
+
+impl<T> LFNode<T> {
+    fn alloc(key: Option<T>, next: *mut LFNode<T>) -> *mut LFNode<T> {
+        Box::into_raw(Box::new(LFNode {
+            key,
+            next: AtomicMarkablePtr::new(next, false),
+        }))
+    }
+}
+
+impl<T: PartialOrd> LockFreeList<T> {
+    pub fn new() -> LockFreeList<T> {
+        let tail = LFNode::alloc(None, std::ptr::null_mut());
+        LockFreeList {
+            head: LFNode::alloc(None, tail),
+            collector: Collector::new(),
+        }
+    }
+    // returns adjacent pred < a <= curr, both unmarked when they were read
+    fn find<'g>(&self, a: &T, guard: &'g Guard) -> (&'g LFNode<T>, *mut LFNode<T>) {
+        'retry: loop {
+            let mut pred = unsafe { &*self.head };
+            let mut curr = pred.next.load(Ordering::Acquire).0;
+            loop {
+                let (mut succ, mut removed) = unsafe { &*curr }.next.load(Ordering::Acquire);
+                while removed {
+                    if pred
+                        .next
+                        .compare_exchange(
+                            (curr, false),
+                            (succ, false),
+                            Ordering::AcqRel,
+                            Ordering::Acquire,
+                        )
+                        .is_err()
+                    {
+                        continue 'retry;
+                    }
+                    unsafe { guard.defer_destroy(curr) };
+                    curr = succ;
+                    (succ, removed) = unsafe { &*curr }.next.load(Ordering::Acquire);
+                }
+                if !before(&unsafe { &*curr }.key, a) {
+                    return (pred, curr);
+                }
+                pred = unsafe { &*curr };
+                curr = succ;
+            }
+        }
+    }
+    pub fn add(&self, a: T) -> bool {
+        let guard = self.collector.pin();
+        let node = LFNode::alloc(Some(a), std::ptr::null_mut());
+        let a = unsafe { (*node).key.as_ref().unwrap() };
+        loop {
+            let (pred, curr) = self.find(a, &guard);
+            if unsafe { &*curr }.key.as_ref() == Some(a) {
+                unsafe { drop(Box::from_raw(node)) };
+                return false;
+            }
+            unsafe { &*node }.next.store(curr, false, Ordering::Relaxed);
+            if pred
+                .next
+                .compare_exchange(
+                    (curr, false),
+                    (node, false),
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                )
+                .is_ok()
+            {
+                return true;
+            }
+        }
+    }
+    pub fn remove(&self, a: &T) -> bool {
+        let guard = self.collector.pin();
+        loop {
+            let (pred, curr) = self.find(a, &guard);
+            let node = unsafe { &*curr };
+            if node.key.as_ref() != Some(a) {
+                return false;
+            }
+            let (succ, removed) = node.next.load(Ordering::Acquire);
+            if removed {
+                continue;
+            }
+            // the mark is the linearization point, unlinking is just a courtesy
+            if node
+                .next
+                .compare_exchange(
+                    (succ, false),
+                    (succ, true),
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                )
+                .is_err()
+            {
+                continue;
+            }
+            if pred
+                .next
+                .compare_exchange(
+                    (curr, false),
+                    (succ, false),
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                )
+                .is_ok()
+            {
+                unsafe { guard.defer_destroy(curr) };
+            } else {
+                self.find(a, &guard);
+            }
+            return true;
+        }
+    }
+    // wait free, walks over marked nodes without helping
+    pub fn contains(&self, a: &T) -> bool {
+        let _guard = self.collector.pin();
+        let mut curr = unsafe { &*(*self.head).next.load(Ordering::Acquire).0 };
+        while before(&curr.key, a) {
+            curr = unsafe { &*curr.next.load(Ordering::Acquire).0 };
+        }
+        curr.key.as_ref() == Some(a) && !curr.next.load(Ordering::Acquire).1
+    }
+    pub fn len(&self) -> usize {
+        let _guard = self.collector.pin();
+        let mut count = 0;
+        let mut node = unsafe { &*(*self.head).next.load(Ordering::Acquire).0 };
+        while node.key.is_some() {
+            let (next, removed) = node.next.load(Ordering::Acquire);
+            if !removed {
+                count += 1;
+            }
+            node = unsafe { &*next };
+        }
+        count
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+impl<T: PartialOrd> ConcurrentSet<T> for LockFreeList<T> {
+    fn add(&self, a: T) -> bool {
+        LockFreeList::add(self, a)
+    }
+    fn remove(&self, a: &T) -> bool {
+        LockFreeList::remove(self, a)
+    }
+    fn contains(&self, a: &T) -> bool {
+        LockFreeList::contains(self, a)
+    }
+}
+impl<T: PartialOrd> Default for LockFreeList<T> {
+    fn default() -> LockFreeList<T> {
+        LockFreeList::new()
+    }
+}
+// marked nodes that are still linked go with the rest, the unlinked ones are in the collector
+impl<T> Drop for LockFreeList<T> {
+    fn drop(&mut self) {
+        let mut node = self.head;
+        while !node.is_null() {
+            let b = unsafe { Box::from_raw(node) };
+            node = b.next.load(Ordering::Relaxed).0;
+        }
+    }
+}
+
+// every thread owns the keys that are equal to its id mod threads, so even while the others
+// run each of its operations has to answer exactly like the CoarseList next to it
+#[test]
+pub fn lock_free_test() {
+    use rand::Rng;
+    let threads = 4;
+    let e = Arc::new(LockFreeList::<usize>::new());
+    let oracle = Arc::new(CoarseList::<usize>::new());
+    let mut jhs = vec![];
+    for t in 0..threads {
+        let l = e.clone();
+        let o = oracle.clone();
+        jhs.push(thread::spawn(move || {
+            let mut rng = rand::thread_rng();
+            for _ in 0..3000 {
+                let key = rng.gen_range(0, 50) * threads + t;
+                match rng.gen_range(0, 3) {
+                    0 => assert_eq!(l.add(key), ConcurrentSet::add(&*o, key)),
+                    1 => assert_eq!(l.remove(&key), o.remove(&key)),
+                    _ => assert_eq!(l.contains(&key), o.contains(&key)),
+                }
+            }
+        }))
+    }
+    for jh in jhs {
+        jh.join().unwrap()
+    }
+    assert_eq!(e.len(), oracle.len());
+    assert!((0..200).all(|i| e.contains(&i) == oracle.contains(&i)));
+}
+
+// a key that counts its drops, compared by the number only
+#[cfg(test)]
+struct Key(usize, Counted);
+#[cfg(test)]
+impl PartialEq for Key {
+    fn eq(&self, other: &Key) -> bool {
+        self.0 == other.0
+    }
+}
+#[cfg(test)]
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Key) -> Option<std::cmp::Ordering> {
+        self.0.partial_cmp(&other.0)
+    }
+}
+
+// all threads fight over the same few keys, removed nodes are freed while the set is in use
+// and every node has to be dropped exactly once
+#[cfg(test)]
+fn set_reclaim_test<S>()
+where
+    S: ConcurrentSet<Key> + Default + Send + Sync + 'static,
+{
+    let dropped = Arc::new(AtomicUsize::new(0));
+    let e = Arc::new(S::default());
+    let mut jhs = vec![];
+    for _ in 0..4 {
+        let l = e.clone();
+        let d = dropped.clone();
+        jhs.push(thread::spawn(move || {
+            let mut added = 0;
+            for i in 0..2000 {
+                if l.add(Key(i % 8, Counted(d.clone()))) {
+                    added += 1;
+                }
+                l.remove(&Key(i % 8, Counted(d.clone())));
+            }
+            added
+        }))
+    }
+    let added: usize = jhs.into_iter().map(|jh| jh.join().unwrap()).sum();
+    assert!(dropped.load(Ordering::SeqCst) > 0);
+    let probes = Arc::new(AtomicUsize::new(0));
+    let remaining = (0..8)
+        .filter(|&i| e.contains(&Key(i, Counted(probes.clone()))))
+        .count();
+    drop(e);
+    // every call allocated a key: 4 * 2000 adds and as many probes for remove
+    assert_eq!(dropped.load(Ordering::SeqCst), 4 * 2000 * 2);
+    assert!(added >= remaining);
+}
+#[test]
+pub fn optimistic_reclaim_test() {
+    set_reclaim_test::<OptimisticList<Key>>();
+}
+#[test]
+pub fn lazy_reclaim_test() {
+    set_reclaim_test::<LazyList<Key>>();
+}
+#[test]
+pub fn lock_free_reclaim_test() {
+    set_reclaim_test::<LockFreeList<Key>>();
+}
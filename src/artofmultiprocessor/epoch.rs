@@ -0,0 +1,235 @@
+use std::sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+// epoch based reclamation for the lock-free structures.
+// a thread pins itself before it touches shared nodes and announces the global epoch it saw.
+// the global epoch only moves on once every pinned thread has seen the current one,
+// so whatever was unlinked and retired two epochs ago can't be reached by anybody anymore.
+// one Collector per data structure, threads get a participant record for the length of a pin
+pub struct Collector {
+    epoch: AtomicUsize,
+    participants: AtomicPtr<Participant>,
+    garbage: AtomicPtr<Deferred>,
+    collecting: AtomicBool,
+    retired: AtomicUsize,
+}
+
+struct Participant {
+    in_use: AtomicBool,
+    // epoch << 1 | 1 while pinned, 0 otherwise
+    epoch: AtomicUsize,
+    next: *mut Participant,
+}
+
+struct Deferred {
+    epoch: usize,
+    ptr: *mut u8,
+    destroy: unsafe fn(*mut u8),
+    next: *mut Deferred,
+}
+
+pub struct Guard<'a> {
+    collector: &'a Collector,
+    participant: &'a Participant,
+}
+
+// how many retired nodes between two collections
+const COLLECT_EVERY: usize = 64;
+
+unsafe fn destroy_box<T>(ptr: *mut u8) {
+    drop(Box::from_raw(ptr as *mut T))
+}
+
+impl Collector {
+    pub fn new() -> Collector {
+        Collector {
+            epoch: AtomicUsize::new(0),
+            participants: AtomicPtr::new(std::ptr::null_mut()),
+            garbage: AtomicPtr::new(std::ptr::null_mut()),
+            collecting: AtomicBool::new(false),
+            retired: AtomicUsize::new(0),
+        }
+    }
+    // reuses a free record or pushes a new one, records live as long as the collector
+    fn claim(&self) -> &Participant {
+        let mut p = self.participants.load(Ordering::Acquire);
+        while !p.is_null() {
+            let participant = unsafe { &*p };
+            if participant
+                .in_use
+                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                return participant;
+            }
+            p = participant.next;
+        }
+        let new = Box::into_raw(Box::new(Participant {
+            in_use: AtomicBool::new(true),
+            epoch: AtomicUsize::new(0),
+            next: std::ptr::null_mut(),
+        }));
+        let mut head = self.participants.load(Ordering::Relaxed);
+        loop {
+            unsafe { (*new).next = head };
+            match self.participants.compare_exchange_weak(
+                head,
+                new,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return unsafe { &*new },
+                Err(h) => head = h,
+            }
+        }
+    }
+    pub fn pin(&self) -> Guard<'_> {
+        let participant = self.claim();
+        let epoch = self.epoch.load(Ordering::Relaxed);
+        participant.epoch.store(epoch << 1 | 1, Ordering::Relaxed);
+        // the announcement has to be visible before we read any shared pointer
+        fence(Ordering::SeqCst);
+        Guard {
+            collector: self,
+            participant,
+        }
+    }
+    fn try_advance(&self) -> usize {
+        let epoch = self.epoch.load(Ordering::Relaxed);
+        fence(Ordering::SeqCst);
+        let mut p = self.participants.load(Ordering::Acquire);
+        while !p.is_null() {
+            let participant = unsafe { &*p };
+            // acquire pairs with the release in Guard::drop, whatever a thread did
+            // before unpinning happens before we free anything it could have seen
+            let e = participant.epoch.load(Ordering::Acquire);
+            if e & 1 == 1 && e >> 1 != epoch {
+                return epoch;
+            }
+            p = participant.next;
+        }
+        match self
+            .epoch
+            .compare_exchange(epoch, epoch + 1, Ordering::Release, Ordering::Relaxed)
+        {
+            Ok(_) => epoch + 1,
+            Err(e) => e,
+        }
+    }
+    fn push_garbage(&self, deferred: *mut Deferred) {
+        let mut head = self.garbage.load(Ordering::Relaxed);
+        loop {
+            unsafe { (*deferred).next = head };
+            match self.garbage.compare_exchange_weak(
+                head,
+                deferred,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(h) => head = h,
+            }
+        }
+    }
+    // frees what was retired at least two epochs ago, only one thread collects at a time
+    pub fn collect(&self) {
+        if self.collecting.swap(true, Ordering::Acquire) {
+            return;
+        }
+        let epoch = self.try_advance();
+        let mut d = self.garbage.swap(std::ptr::null_mut(), Ordering::Acquire);
+        while !d.is_null() {
+            let next = unsafe { (*d).next };
+            if unsafe { (*d).epoch } + 2 <= epoch {
+                let deferred = unsafe { Box::from_raw(d) };
+                unsafe { (deferred.destroy)(deferred.ptr) };
+            } else {
+                self.push_garbage(d);
+            }
+            d = next;
+        }
+        self.collecting.store(false, Ordering::Release);
+    }
+}
+impl Default for Collector {
+    fn default() -> Collector {
+        Collector::new()
+    }
+}
+// nobody can be pinned anymore
+impl Drop for Collector {
+    fn drop(&mut self) {
+        let mut d = *self.garbage.get_mut();
+        while !d.is_null() {
+            let deferred = unsafe { Box::from_raw(d) };
+            unsafe { (deferred.destroy)(deferred.ptr) };
+            d = deferred.next;
+        }
+        let mut p = *self.participants.get_mut();
+        while !p.is_null() {
+            let participant = unsafe { Box::from_raw(p) };
+            p = participant.next;
+        }
+    }
+}
+unsafe impl Send for Collector {}
+unsafe impl Sync for Collector {}
+
+impl<'a> Guard<'a> {
+    // ptr came from Box::into_raw and is already unlinked, so only threads pinned right now
+    // can still see it. it is dropped once they are all gone
+    pub unsafe fn defer_destroy<T>(&self, ptr: *mut T) {
+        let deferred = Box::into_raw(Box::new(Deferred {
+            epoch: self.collector.epoch.load(Ordering::SeqCst),
+            ptr: ptr as *mut u8,
+            destroy: destroy_box::<T>,
+            next: std::ptr::null_mut(),
+        }));
+        self.collector.push_garbage(deferred);
+        if self
+            .collector
+            .retired
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(COLLECT_EVERY)
+        {
+            self.collector.collect()
+        }
+    }
+}
+impl<'a> Drop for Guard<'a> {
+    fn drop(&mut self) {
+        self.participant.epoch.store(0, Ordering::Release);
+        self.participant.in_use.store(false, Ordering::Release);
+    }
+}
+
+// counts its drops, for the tests of everything that frees through a Collector
+#[cfg(test)]
+pub(crate) struct Counted(pub(crate) std::sync::Arc<AtomicUsize>);
+#[cfg(test)]
+impl Drop for Counted {
+    fn drop(&mut self) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+// a node retired while someone is pinned survives until that guard is gone
+#[test]
+fn epoch_test() {
+    use std::sync::Arc;
+    let dropped = Arc::new(AtomicUsize::new(0));
+    let collector = Collector::new();
+    let reader = collector.pin();
+    {
+        let guard = collector.pin();
+        unsafe { guard.defer_destroy(Box::into_raw(Box::new(Counted(dropped.clone())))) };
+    }
+    for _ in 0..5 {
+        collector.collect();
+    }
+    assert_eq!(dropped.load(Ordering::SeqCst), 0);
+    drop(reader);
+    for _ in 0..3 {
+        collector.collect();
+    }
+    assert_eq!(dropped.load(Ordering::SeqCst), 1);
+}
@@ -1,5 +1,9 @@
+pub mod bench;
 pub mod ch10;
+pub mod ch11;
 pub mod ch2;
 pub mod ch7;
 pub mod ch8;
 pub mod ch9;
+pub mod epoch;
+pub mod tagged;
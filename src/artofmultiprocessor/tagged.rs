@@ -0,0 +1,283 @@
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+
+// AtomicStampedReference. the pointer and a 16 bit stamp share one word, the stamp in the
+// top bits that user space addresses never use on 64 bit targets. bumping the stamp on every
+// change makes a cas fail if the pointer went away and came back in between (aba).
+// the stamp wraps after 65536 changes, a thread would have to sleep through exactly that many.
+// addresses have to fit in the low 48 bits. user space addresses on x86_64 and aarch64 do,
+// unless a mapping above that was asked for on purpose (la57, pointer tagging).
+// pack is the only place that checks it, new, store and compare_exchange hand back a pointer
+// that doesn't fit instead of storing it
+pub struct AtomicStampedPtr<T> {
+    packed: AtomicU64,
+    phantom: PhantomData<*mut T>,
+}
+unsafe impl<T> Send for AtomicStampedPtr<T> {}
+unsafe impl<T> Sync for AtomicStampedPtr<T> {}
+
+// why a compare_exchange on an AtomicStampedPtr failed
+#[derive(Debug)]
+pub enum StampedCasError<T> {
+    // the pair that was there instead of current
+    Changed(*mut T, u16),
+    // the new pointer doesn't fit, nothing was changed
+    TooWide(*mut T),
+}
+
+const STAMP_SHIFT: u32 = 48;
+const ADDRESS_MASK: u64 = (1 << STAMP_SHIFT) - 1;
+
+impl<T> AtomicStampedPtr<T> {
+    // Err gives ptr back if it doesn't fit
+    fn pack(ptr: *mut T, stamp: u16) -> Result<u64, *mut T> {
+        if !Self::fits(ptr) {
+            return Err(ptr);
+        }
+        Ok(ptr.expose_provenance() as u64 | (stamp as u64) << STAMP_SHIFT)
+    }
+    fn unpack(packed: u64) -> (*mut T, u16) {
+        let ptr = std::ptr::with_exposed_provenance_mut((packed & ADDRESS_MASK) as usize);
+        (ptr, (packed >> STAMP_SHIFT) as u16)
+    }
+    // whether ptr leaves the top 16 bits to the stamp, null always does
+    pub fn fits(ptr: *mut T) -> bool {
+        ptr.addr() as u64 & !ADDRESS_MASK == 0
+    }
+    pub fn new(ptr: *mut T, stamp: u16) -> Result<AtomicStampedPtr<T>, *mut T> {
+        Ok(AtomicStampedPtr {
+            packed: AtomicU64::new(Self::pack(ptr, stamp)?),
+            phantom: PhantomData,
+        })
+    }
+    pub fn load(&self, order: Ordering) -> (*mut T, u16) {
+        Self::unpack(self.packed.load(order))
+    }
+    pub fn store(&self, ptr: *mut T, stamp: u16, order: Ordering) -> Result<(), *mut T> {
+        self.packed.store(Self::pack(ptr, stamp)?, order);
+        Ok(())
+    }
+    // succeeds only if both pointer and stamp still match, returns the pair it found
+    pub fn compare_exchange(
+        &self,
+        current: (*mut T, u16),
+        new: (*mut T, u16),
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<(*mut T, u16), StampedCasError<T>> {
+        let new = Self::pack(new.0, new.1).map_err(StampedCasError::TooWide)?;
+        let changed = |(ptr, stamp)| StampedCasError::Changed(ptr, stamp);
+        // a current that doesn't fit can't be in there
+        let current = match Self::pack(current.0, current.1) {
+            Ok(current) => current,
+            Err(_) => return Err(changed(self.load(failure))),
+        };
+        self.packed
+            .compare_exchange(current, new, success, failure)
+            .map(Self::unpack)
+            .map_err(|found| changed(Self::unpack(found)))
+    }
+}
+
+// AtomicMarkableReference. the mark lives in the lowest bit of the pointer,
+// which is free for anything aligned to at least two bytes. new refuses other types at compile time
+pub struct AtomicMarkablePtr<T> {
+    ptr: AtomicPtr<T>,
+}
+
+impl<T> AtomicMarkablePtr<T> {
+    const ALIGNED: () = assert!(
+        std::mem::align_of::<T>() >= 2,
+        "AtomicMarkablePtr needs the low bit of the address"
+    );
+    fn pack(ptr: *mut T, mark: bool) -> *mut T {
+        ptr.map_addr(|a| a | mark as usize)
+    }
+    fn unpack(ptr: *mut T) -> (*mut T, bool) {
+        (ptr.map_addr(|a| a & !1), ptr.addr() & 1 == 1)
+    }
+    pub fn new(ptr: *mut T, mark: bool) -> AtomicMarkablePtr<T> {
+        let () = Self::ALIGNED;
+        AtomicMarkablePtr {
+            ptr: AtomicPtr::new(Self::pack(ptr, mark)),
+        }
+    }
+    pub fn load(&self, order: Ordering) -> (*mut T, bool) {
+        Self::unpack(self.ptr.load(order))
+    }
+    pub fn store(&self, ptr: *mut T, mark: bool, order: Ordering) {
+        self.ptr.store(Self::pack(ptr, mark), order)
+    }
+    pub fn compare_exchange(
+        &self,
+        current: (*mut T, bool),
+        new: (*mut T, bool),
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<(*mut T, bool), (*mut T, bool)> {
+        self.ptr
+            .compare_exchange(
+                Self::pack(current.0, current.1),
+                Self::pack(new.0, new.1),
+                success,
+                failure,
+            )
+            .map(Self::unpack)
+            .map_err(Self::unpack)
+    }
+    // sets the mark if the pointer is still ptr, true if it is marked afterwards
+    pub fn attempt_mark(&self, ptr: *mut T, mark: bool) -> bool {
+        let (current, current_mark) = self.load(Ordering::Acquire);
+        current == ptr
+            && (current_mark == mark
+                || self
+                    .compare_exchange(
+                        (ptr, current_mark),
+                        (ptr, mark),
+                        Ordering::AcqRel,
+                        Ordering::Acquire,
+                    )
+                    .is_ok())
+    }
+}
+
+// a stack a -> b -> c. a slow pop reads a and its successor b, then the others pop a, pop b
+// and push a back. with a plain pointer the slow pop still finds a on top and installs b,
+// which isn't in the stack anymore. the stamp catches it
+#[test]
+fn aba() {
+    struct N {
+        next: *mut N,
+    }
+    let alloc = |next| Box::into_raw(Box::new(N { next }));
+    let c = alloc(std::ptr::null_mut());
+    let b = alloc(c);
+    let a = alloc(b);
+
+    let head = AtomicPtr::new(a);
+    let (first, next) = (head.load(Ordering::Acquire), unsafe { (*a).next });
+    head.store(b, Ordering::Release);
+    head.store(c, Ordering::Release);
+    unsafe { (*a).next = c };
+    head.store(a, Ordering::Release);
+    assert!(head
+        .compare_exchange(first, next, Ordering::AcqRel, Ordering::Acquire)
+        .is_ok());
+    assert_eq!(head.load(Ordering::Acquire), b);
+
+    unsafe { (*a).next = b };
+    let head = AtomicStampedPtr::new(a, 0).unwrap();
+    let (first, stamp) = head.load(Ordering::Acquire);
+    let next = unsafe { (*first).next };
+    head.store(b, stamp + 1, Ordering::Release).unwrap();
+    head.store(c, stamp + 2, Ordering::Release).unwrap();
+    unsafe { (*a).next = c };
+    head.store(a, stamp + 3, Ordering::Release).unwrap();
+    assert!(matches!(
+        head.compare_exchange(
+            (first, stamp),
+            (next, stamp + 1),
+            Ordering::AcqRel,
+            Ordering::Acquire
+        ),
+        Err(StampedCasError::Changed(p, s)) if p == a && s == stamp + 3
+    ));
+    // retrying with what is really there pops a for real
+    let (first, stamp) = head.load(Ordering::Acquire);
+    let next = unsafe { (*first).next };
+    assert!(head
+        .compare_exchange(
+            (first, stamp),
+            (next, stamp.wrapping_add(1)),
+            Ordering::AcqRel,
+            Ordering::Acquire
+        )
+        .is_ok());
+    assert_eq!(head.load(Ordering::Acquire), (c, stamp + 1));
+    for n in [a, b, c] {
+        unsafe { drop(Box::from_raw(n)) }
+    }
+}
+
+// threads bump the stamp of the same pointer, no increment may get lost
+#[test]
+fn stamped_concurrent() {
+    use std::{sync::Arc, thread};
+    let mut value = 7;
+    let target = &mut value as *mut i32;
+    let shared = Arc::new(AtomicStampedPtr::new(target, 0).unwrap());
+    let jhs: Vec<_> = (0..4)
+        .map(|_| {
+            let s = shared.clone();
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    let mut current = s.load(Ordering::Acquire);
+                    while let Err(StampedCasError::Changed(p, stamp)) = s.compare_exchange(
+                        current,
+                        (current.0, current.1.wrapping_add(1)),
+                        Ordering::AcqRel,
+                        Ordering::Acquire,
+                    ) {
+                        current = (p, stamp);
+                        thread::yield_now()
+                    }
+                }
+            })
+        })
+        .collect();
+    for jh in jhs {
+        jh.join().unwrap()
+    }
+    assert_eq!(shared.load(Ordering::Acquire), (target, 4000));
+}
+
+// an address that needs the stamp bits is handed back and the stored pair stays as it was
+#[cfg(target_pointer_width = "64")]
+#[test]
+fn stamped_too_wide() {
+    let wide = std::ptr::without_provenance_mut::<u64>(1 << 50);
+    assert!(!AtomicStampedPtr::fits(wide));
+    assert_eq!(AtomicStampedPtr::new(wide, 0).err(), Some(wide));
+    let mut value = 7u64;
+    let target = &mut value as *mut u64;
+    let s = AtomicStampedPtr::new(target, 0).unwrap();
+    assert_eq!(s.store(wide, 1, Ordering::Release), Err(wide));
+    assert!(matches!(
+        s.compare_exchange((target, 0), (wide, 1), Ordering::AcqRel, Ordering::Acquire),
+        Err(StampedCasError::TooWide(p)) if p == wide
+    ));
+    assert!(matches!(
+        s.compare_exchange((wide, 0), (target, 1), Ordering::AcqRel, Ordering::Acquire),
+        Err(StampedCasError::Changed(p, 0)) if p == target
+    ));
+    assert_eq!(s.load(Ordering::Acquire), (target, 0));
+}
+
+#[test]
+fn markable() {
+    let mut value = 7u64;
+    let target = &mut value as *mut u64;
+    let m = AtomicMarkablePtr::new(target, false);
+    assert_eq!(m.load(Ordering::Acquire), (target, false));
+    assert!(!m.attempt_mark(std::ptr::null_mut(), true));
+    assert!(m.attempt_mark(target, true));
+    assert_eq!(m.load(Ordering::Acquire), (target, true));
+    assert_eq!(
+        m.compare_exchange(
+            (target, false),
+            (std::ptr::null_mut(), false),
+            Ordering::AcqRel,
+            Ordering::Acquire
+        ),
+        Err((target, true))
+    );
+    assert!(m
+        .compare_exchange(
+            (target, true),
+            (std::ptr::null_mut(), false),
+            Ordering::AcqRel,
+            Ordering::Acquire
+        )
+        .is_ok());
+    assert_eq!(m.load(Ordering::Acquire), (std::ptr::null_mut(), false));
+}
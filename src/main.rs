@@ -4,10 +4,16 @@ mod concurrent;
 mod pointers;
 mod datastructures;
 
-use crate::artofmultiprocessor::ch2::*;
-use crate::artofmultiprocessor::ch7::*;
-use crate::artofmultiprocessor::ch8::*;
-use crate::artofmultiprocessor::ch9::*;
-use std::thread;
+use crate::artofmultiprocessor::bench;
 
-fn main() {}
+// runs the lock benchmark matrix, see bench::Config::from_args for the flags
+fn main() {
+    let config = match bench::Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1)
+        }
+    };
+    bench::run(&config, &mut std::io::stdout()).unwrap()
+}
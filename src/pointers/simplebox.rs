@@ -1,9 +1,8 @@
 use std::alloc::{alloc, dealloc, realloc, Layout};
 use std::{
     fmt::Display,
-    intrinsics::drop_in_place,
     ops::{Deref, DerefMut},
-    ptr::NonNull,
+    ptr::{drop_in_place, NonNull},
 };
 
 /// figure out how to work with trait objects / slices